@@ -5,6 +5,14 @@ use reqwest::{Error, RequestBuilder, Response};
 use tracing::error;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use crate::secure::{
+    SecureError,
+    signature::{
+        CONTENT_DIGEST_HEADER, RequestSigner, SIGNATURE_HEADER, SIGNATURE_INPUT_HEADER,
+        SignedRequest,
+    },
+};
+
 macro_rules! dyn_event {
     ($lvl:ident, $($arg:tt)+) => {
         match $lvl {
@@ -107,14 +115,140 @@ pub async fn send_http(
     result
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum SignedHttpError {
+    #[error(transparent)]
+    Http(#[from] Error),
+    #[error("streaming request bodies cannot be signed")]
+    StreamingBody,
+    #[error("failed to sign request: {0}")]
+    Sign(#[from] SecureError),
+}
+
+/// Send the request like [`send_http`], signed with `signer` so the receiver
+/// can authenticate it with [`verify_signature`](crate::server::verify_signature).
+///
+/// Only buffered bodies can be signed. A request is never sent unsigned: a
+/// streaming body or a signing failure is returned as an error instead.
+pub async fn send_signed_http(
+    builder: RequestBuilder,
+    route_template: Option<&str>,
+    signer: &RequestSigner,
+) -> Result<Response, SignedHttpError> {
+    let (client, req) = builder.build_split();
+    let mut req = req?;
+
+    let body = req
+        .body()
+        .map_or(Some(&[][..]), |body| body.as_bytes())
+        .ok_or(SignedHttpError::StreamingBody)?;
+    let signed = signer.sign(&SignedRequest {
+        method: req.method().as_str(),
+        path: req.url().path(),
+        query: req.url().query(),
+        body,
+    })?;
+
+    let headers = req.headers_mut();
+    for (name, value) in [
+        (CONTENT_DIGEST_HEADER, signed.content_digest),
+        (SIGNATURE_INPUT_HEADER, signed.signature_input),
+        (SIGNATURE_HEADER, signed.signature),
+    ] {
+        let value = http::HeaderValue::from_str(&value)
+            .map_err(|_| SignedHttpError::Sign(SecureError::InvalidSignature))?;
+        headers.insert(name, value);
+    }
+
+    Ok(send_http(RequestBuilder::from_parts(client, req), route_template).await?)
+}
+
 struct HeaderInjector<'a>(&'a mut http::HeaderMap);
 
 impl Injector for HeaderInjector<'_> {
     fn set(&mut self, key: &str, value: String) {
-        if let Ok(name) = http::header::HeaderName::from_bytes(key.as_bytes()) {
-            if let Ok(val) = http::header::HeaderValue::from_str(&value) {
-                self.0.insert(name, val);
-            }
+        if let Ok(name) = http::header::HeaderName::from_bytes(key.as_bytes())
+            && let Ok(val) = http::header::HeaderValue::from_str(&value)
+        {
+            self.0.insert(name, val);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        secure::signature::SignatureVerifier,
+        server::{Subject, verify_signature},
+    };
+    use axum::{Extension, Router, routing::post};
+    use std::{sync::Arc, time::Duration};
+    use tokio::net::TcpListener;
+
+    const SECRET: &[u8] = &[9; 32];
+
+    async fn receiver() -> String {
+        let verifier = SignatureVerifier::new(Duration::from_secs(30))
+            .with_key("client", SECRET)
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/orders",
+                post(
+                    |Extension(Subject(subject)): Extension<Subject>, body: String| async move {
+                        format!("{} {}", subject, body)
+                    },
+                ),
+            )
+            .layer(verify_signature(Arc::new(verifier)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/orders", addr)
+    }
+
+    #[tokio::test]
+    async fn signed_request_is_accepted() {
+        let url = receiver().await;
+        let signer = RequestSigner::new("client", SECRET).unwrap();
+        let builder = reqwest::Client::new()
+            .post(format!("{}?dry_run=true", url))
+            .body("amount=10");
+
+        let response = send_signed_http(builder, Some("/orders"), &signer)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "client amount=10");
+    }
+
+    #[tokio::test]
+    async fn unsigned_or_wrongly_signed_request_is_rejected() {
+        let url = receiver().await;
+        let response = send_http(reqwest::Client::new().post(&url).body("x"), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        let stranger = RequestSigner::new("client", &[8; 32]).unwrap();
+        let builder = reqwest::Client::new().post(&url).body("x");
+        let response = send_signed_http(builder, None, &stranger).await.unwrap();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn streaming_body_is_never_sent_unsigned() {
+        let url = receiver().await;
+        let signer = RequestSigner::new("client", SECRET).unwrap();
+        // Another response's body is streamed rather than buffered.
+        let upstream = reqwest::get(&url).await.unwrap();
+        let builder = reqwest::Client::new()
+            .post(&url)
+            .body(reqwest::Body::from(upstream));
+        assert!(matches!(
+            send_signed_http(builder, None, &signer).await,
+            Err(SignedHttpError::StreamingBody)
+        ));
+    }
+}
@@ -0,0 +1,346 @@
+use parking_lot::RwLock;
+use reqwest::{Client, header::CACHE_CONTROL};
+use rustc_hash::FxHashMap;
+use serde::de::DeserializeOwned;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use super::send_http;
+use crate::secure::{
+    SecureError,
+    jwt::{self, Claims, JwkSet, Key, Validation},
+};
+
+/// Used when the issuer does not send a `max-age`.
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Minimum time between two fetches, successful or not, so tokens with made
+/// up key ids or an unreachable issuer cannot be used to hammer it.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwksError {
+    #[error("failed to fetch jwks: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("jwks endpoint returned status {0}")]
+    Status(u16),
+    #[error("malformed jwks document")]
+    InvalidDocument,
+    #[error(transparent)]
+    Secure(#[from] SecureError),
+}
+
+#[derive(Default)]
+struct Cache {
+    keys: FxHashMap<String, Arc<Key>>,
+    /// Start of the last fetch, whether it succeeded or not.
+    attempted_at: Option<Instant>,
+    expires_at: Option<Instant>,
+}
+
+/// Verifies JWTs against the keys published by an issuer's JWKS endpoint.
+///
+/// Keys are cached for as long as the endpoint's `Cache-Control` allows and
+/// refetched early when a token names a `kid` that is not cached yet, so the
+/// issuer can rotate keys without coordinating deploys. The last key set
+/// fetched successfully keeps being served until a refresh succeeds.
+pub struct JwksClient {
+    url: String,
+    http: Client,
+    cache: RwLock<Cache>,
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl JwksClient {
+    pub fn new(url: impl Into<String>) -> Result<Self, JwksError> {
+        let http = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(JwksClient::with_client(url, http))
+    }
+
+    /// Use `http` to fetch the keys. It should have timeouts set, since
+    /// verification waits on the fetch.
+    pub fn with_client(url: impl Into<String>, http: Client) -> Self {
+        JwksClient {
+            url: url.into(),
+            http,
+            cache: RwLock::new(Cache::default()),
+            refresh: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub async fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<Claims<T>, JwksError> {
+        let header = jwt::decode_header(token)?;
+        let kid = header.kid.ok_or(SecureError::UnknownKey)?;
+        let key = self.key(&kid).await?;
+        Ok(jwt::decode(token, &key, validation)?)
+    }
+
+    pub async fn key(&self, kid: &str) -> Result<Arc<Key>, JwksError> {
+        let (cached, fresh) = {
+            let cache = self.cache.read();
+            (
+                cache.keys.get(kid).cloned(),
+                cache.expires_at.is_some_and(|at| Instant::now() < at),
+            )
+        };
+        if let (Some(key), true) = (&cached, fresh) {
+            return Ok(key.clone());
+        }
+
+        match self.refresh(kid).await {
+            Ok(Some(key)) => Ok(key),
+            Ok(None) => Err(SecureError::UnknownKey.into()),
+            Err(err) => match cached {
+                Some(key) => {
+                    warn!(
+                        error.message = err.to_string(),
+                        url.full = self.url,
+                        "serving stale jwks key {}",
+                        kid
+                    );
+                    Ok(key)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Fetch the key set unless a fetch was attempted in the last
+    /// [`MIN_REFETCH_INTERVAL`], then look up `kid` in the cache.
+    async fn refresh(&self, kid: &str) -> Result<Option<Arc<Key>>, JwksError> {
+        let _guard = self.refresh.lock().await;
+
+        // Another task may have fetched while this one waited, or the issuer
+        // was asked too recently.
+        {
+            let mut cache = self.cache.write();
+            let now = Instant::now();
+            if cache
+                .attempted_at
+                .is_some_and(|at| now.duration_since(at) < MIN_REFETCH_INTERVAL)
+            {
+                return Ok(cache.keys.get(kid).cloned());
+            }
+            cache.attempted_at = Some(now);
+        }
+
+        let (keys, ttl) = self.fetch().await?;
+
+        let mut cache = self.cache.write();
+        cache.keys = keys;
+        cache.expires_at = Instant::now().checked_add(ttl);
+        Ok(cache.keys.get(kid).cloned())
+    }
+
+    async fn fetch(&self) -> Result<(FxHashMap<String, Arc<Key>>, Duration), JwksError> {
+        let response = send_http(self.http.get(&self.url), None).await?;
+        if !response.status().is_success() {
+            return Err(JwksError::Status(response.status().as_u16()));
+        }
+        let ttl = response
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .map(cache_ttl)
+            .unwrap_or(DEFAULT_TTL);
+        let body = response.bytes().await?;
+        let jwks: JwkSet = serde_json::from_slice(&body).map_err(|_| JwksError::InvalidDocument)?;
+
+        let keys = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| Key::from_jwk(jwk).ok())
+            .map(|key| (key.kid().to_owned(), Arc::new(key)))
+            .collect();
+        Ok((keys, ttl))
+    }
+}
+
+fn cache_ttl(cache_control: &str) -> Duration {
+    let mut ttl = DEFAULT_TTL;
+    for directive in cache_control.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-store" || directive == "no-cache" {
+            return Duration::ZERO;
+        }
+        if let Some(max_age) = directive.strip_prefix("max-age=")
+            && let Ok(secs) = max_age.trim_matches('"').parse::<u64>()
+        {
+            ttl = Duration::from_secs(secs).min(MAX_TTL);
+        }
+    }
+    ttl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, extract::State, http::StatusCode, response::IntoResponse, routing::get};
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    struct Issuer {
+        jwks: Mutex<JwkSet>,
+        cache_control: Mutex<Option<&'static str>>,
+        status: Mutex<StatusCode>,
+        hits: AtomicUsize,
+    }
+
+    impl Issuer {
+        fn publish(&self, keys: &[&Key]) {
+            self.jwks.lock().keys = keys.iter().filter_map(|key| key.to_jwk()).collect();
+        }
+
+        fn hits(&self) -> usize {
+            self.hits.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn serve_jwks(State(issuer): State<Arc<Issuer>>) -> impl IntoResponse {
+        issuer.hits.fetch_add(1, Ordering::SeqCst);
+        let mut response = axum::Json(issuer.jwks.lock().clone()).into_response();
+        *response.status_mut() = *issuer.status.lock();
+        if let Some(cache_control) = *issuer.cache_control.lock() {
+            response
+                .headers_mut()
+                .insert(CACHE_CONTROL, cache_control.parse().unwrap());
+        }
+        response
+    }
+
+    async fn issuer() -> (Arc<Issuer>, JwksClient) {
+        let issuer = Arc::new(Issuer {
+            jwks: Mutex::new(JwkSet::default()),
+            cache_control: Mutex::new(None),
+            status: Mutex::new(StatusCode::OK),
+            hits: AtomicUsize::new(0),
+        });
+        let app = Router::new()
+            .route("/jwks.json", get(serve_jwks))
+            .with_state(issuer.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = JwksClient::new(format!("http://{}/jwks.json", addr)).unwrap();
+        (issuer, client)
+    }
+
+    fn key(kid: &str) -> Key {
+        Key::generate_ed25519(kid).unwrap().0
+    }
+
+    /// Pretend the last fetch happened long enough ago to allow another.
+    fn allow_refetch(client: &JwksClient) {
+        let mut cache = client.cache.write();
+        cache.attempted_at = Instant::now().checked_sub(MIN_REFETCH_INTERVAL);
+    }
+
+    fn expire(client: &JwksClient) {
+        client.cache.write().expires_at = Some(Instant::now());
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetches_rotated_keys() {
+        let (issuer, client) = issuer().await;
+        let (old, new) = (key("old"), key("new"));
+        issuer.publish(&[&old]);
+
+        assert_eq!(client.key("old").await.unwrap().kid(), "old");
+        assert_eq!(client.key("old").await.unwrap().kid(), "old");
+        assert_eq!(issuer.hits(), 1);
+
+        issuer.publish(&[&new]);
+        allow_refetch(&client);
+        assert_eq!(client.key("new").await.unwrap().kid(), "new");
+        assert_eq!(issuer.hits(), 2);
+
+        let claims = Claims::new(serde_json::Map::new(), Duration::from_secs(60));
+        let token = jwt::encode(&new, &claims).unwrap();
+        client
+            .verify::<serde_json::Map<String, serde_json::Value>>(&token, &Validation::default())
+            .await
+            .unwrap();
+        assert_eq!(issuer.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetch_is_throttled() {
+        let (issuer, client) = issuer().await;
+        issuer.publish(&[&key("a")]);
+
+        client.key("a").await.unwrap();
+        for kid in ["b", "c", "d"] {
+            assert!(matches!(
+                client.key(kid).await,
+                Err(JwksError::Secure(SecureError::UnknownKey))
+            ));
+        }
+        assert_eq!(issuer.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_control_sets_expiry() {
+        let (issuer, client) = issuer().await;
+        issuer.publish(&[&key("a")]);
+
+        let cases = [
+            (Some("public, max-age=60"), Some(Duration::from_secs(60))),
+            (Some("max-age=999999999"), Some(MAX_TTL)),
+            (Some("no-store"), Some(Duration::ZERO)),
+            (None, Some(DEFAULT_TTL)),
+        ];
+        for (cache_control, ttl) in cases {
+            *issuer.cache_control.lock() = cache_control;
+            allow_refetch(&client);
+            expire(&client);
+            let before = Instant::now();
+            client.key("a").await.unwrap();
+            let after = Instant::now();
+
+            let expires_at = client.cache.read().expires_at.unwrap();
+            let ttl = ttl.unwrap();
+            assert!(expires_at >= before + ttl, "{:?}", cache_control);
+            assert!(expires_at <= after + ttl, "{:?}", cache_control);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_last_good_keys() {
+        let (issuer, client) = issuer().await;
+        issuer.publish(&[&key("a")]);
+        client.key("a").await.unwrap();
+
+        *issuer.status.lock() = StatusCode::INTERNAL_SERVER_ERROR;
+        allow_refetch(&client);
+        expire(&client);
+        assert_eq!(client.key("a").await.unwrap().kid(), "a");
+        assert!(matches!(
+            client.key("b").await,
+            Err(JwksError::Secure(SecureError::UnknownKey))
+        ));
+        // The failed fetch is throttled like a successful one.
+        assert_eq!(client.key("a").await.unwrap().kid(), "a");
+        assert_eq!(issuer.hits(), 2);
+
+        *issuer.status.lock() = StatusCode::OK;
+        issuer.publish(&[&key("b")]);
+        allow_refetch(&client);
+        assert_eq!(client.key("b").await.unwrap().kid(), "b");
+        assert_eq!(issuer.hits(), 3);
+    }
+}
@@ -1,3 +1,5 @@
 mod http;
+mod jwks;
 
-pub use http::send_http;
+pub use http::{SignedHttpError, send_http, send_signed_http};
+pub use jwks::{JwksClient, JwksError};
@@ -39,6 +39,13 @@ pub fn value() -> Env {
 
 static ENV_LOADED: OnceCell<()> = OnceCell::new();
 
+/// Whether [`parse`] loaded the environment and it is [`Env::Local`]. Unlike
+/// [`value`], which reports `Local` until then, this is `false` before the
+/// environment is loaded, so checks relaxed for development fail closed.
+pub(crate) fn is_local() -> bool {
+    ENV_LOADED.get().is_some() && value() == Env::Local
+}
+
 pub fn parse<T>(config: &mut T)
 where
     T: DeserializeOwned,
@@ -94,11 +101,11 @@ fn read_env_file() {
                     continue;
                 }
                 let mut parts = line.splitn(2, '=');
-                if let Some(key) = parts.next() {
-                    if let Some(value) = parts.next() {
-                        unsafe {
-                            std::env::set_var(key.trim(), value.trim());
-                        }
+                if let Some(key) = parts.next()
+                    && let Some(value) = parts.next()
+                {
+                    unsafe {
+                        std::env::set_var(key.trim(), value.trim());
                     }
                 }
             }
@@ -0,0 +1,411 @@
+use once_cell::sync::OnceCell;
+use opentelemetry::trace::TraceContextExt;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// `prev` of the first record in a log.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A security-relevant event. The struct is serialized as the `event` of
+/// the audit record, next to its `kind`.
+pub trait AuditEvent: Serialize {
+    const KIND: &'static str;
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Login {
+    pub subject: String,
+    pub success: bool,
+    /// How the subject authenticated, such as `password` or `api_key`.
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_address: Option<String>,
+}
+
+impl AuditEvent for Login {
+    const KIND: &'static str = "login";
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OtpFailure {
+    pub subject: String,
+    /// Why the code was rejected, such as `otp_invalid` or `otp_replayed`.
+    pub reason: String,
+}
+
+impl AuditEvent for OtpFailure {
+    const KIND: &'static str = "otp_failure";
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PermissionChange {
+    /// Who made the change.
+    pub actor: String,
+    /// Whose permissions changed.
+    pub subject: String,
+    pub granted: Vec<String>,
+    pub revoked: Vec<String>,
+}
+
+impl AuditEvent for PermissionChange {
+    const KIND: &'static str = "permission_change";
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LogFilterChange {
+    /// Who made the change.
+    pub actor: String,
+    pub filter: String,
+    /// Seconds until the previous filter is restored, for a temporary one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+impl AuditEvent for LogFilterChange {
+    const KIND: &'static str = "log_filter_change";
+}
+
+struct Chain {
+    writer: Box<dyn Write + Send>,
+    seq: u64,
+    prev: String,
+}
+
+impl Chain {
+    /// Link `record` to the previous one and write it as a single line. The
+    /// chain only advances once the line is written.
+    fn append(&mut self, mut record: Map<String, Value>) -> io::Result<()> {
+        let seq = self.seq + 1;
+        record.insert("seq".into(), seq.into());
+        record.insert("prev".into(), self.prev.clone().into());
+        let mut record = Value::Object(record);
+        let hash = hash_record(&self.prev, &record)?;
+        if let Value::Object(fields) = &mut record {
+            fields.insert("hash".into(), hash.clone().into());
+        }
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+
+        self.seq = seq;
+        self.prev = hash;
+        Ok(())
+    }
+}
+
+/// Append-only log of [`AuditEvent`]s, one JSON record per line.
+///
+/// Every record carries the SHA-256 of the one before it, so [`verify`]
+/// detects records that were altered, removed or reordered. Truncating the
+/// end of the log is only detected by comparing the last hash with one kept
+/// elsewhere.
+pub struct AuditLog {
+    chain: Arc<Mutex<Chain>>,
+}
+
+impl AuditLog {
+    /// Write a new chain to `writer`.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        AuditLog {
+            chain: Arc::new(Mutex::new(Chain {
+                writer: Box::new(writer),
+                seq: 0,
+                prev: GENESIS.to_owned(),
+            })),
+        }
+    }
+
+    /// Append to the log at `path`, continuing the chain already in it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let (seq, prev) = match File::open(path) {
+            Ok(file) => last_link(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (0, GENESIS.to_owned()),
+            Err(err) => return Err(err),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog {
+            chain: Arc::new(Mutex::new(Chain {
+                writer: Box::new(file),
+                seq,
+                prev,
+            })),
+        })
+    }
+
+    /// Append `event`, tagged with the current trace. The write happens on a
+    /// blocking thread, and completes even if the returned future is
+    /// dropped.
+    pub async fn record<E: AuditEvent>(&self, event: &E) -> io::Result<()> {
+        let event = serde_json::to_value(event)?;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let trace_id = {
+            let context = tracing::Span::current().context();
+            let span = context.span();
+            let span_context = span.span_context();
+            span_context
+                .is_valid()
+                .then(|| span_context.trace_id().to_string())
+        };
+
+        let mut record = Map::new();
+        record.insert("ts".into(), timestamp.into());
+        record.insert("kind".into(), E::KIND.into());
+        record.insert("event".into(), event);
+        if let Some(trace_id) = trace_id {
+            record.insert("trace_id".into(), trace_id.into());
+        }
+
+        let chain = self.chain.clone();
+        tokio::task::spawn_blocking(move || chain.lock().append(record))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+static AUDIT_LOG: OnceCell<AuditLog> = OnceCell::new();
+
+/// Install the log used by [`record`]. Only the first call has any effect.
+pub fn init(log: AuditLog) {
+    let _ = AUDIT_LOG.set(log);
+}
+
+/// Whether [`init`] installed a log for [`record`].
+pub fn is_initialized() -> bool {
+    AUDIT_LOG.get().is_some()
+}
+
+/// Record `event` in the log installed with [`init`].
+pub async fn record<E: AuditEvent>(event: &E) -> io::Result<()> {
+    AUDIT_LOG
+        .get()
+        .ok_or_else(|| io::Error::other("audit log not initialized"))?
+        .record(event)
+        .await
+}
+
+/// Summary of a log that passed [`verify`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verified {
+    pub records: u64,
+    /// Hash of the last record, to compare with a copy kept elsewhere.
+    pub last_hash: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("failed to read audit log: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {0}: malformed record")]
+    Malformed(u64),
+    #[error("line {0}: record was altered")]
+    Altered(u64),
+    #[error("line {line}: expected sequence {expected}, found {found}")]
+    Sequence {
+        line: u64,
+        expected: u64,
+        found: u64,
+    },
+    #[error("line {0}: does not follow the previous record")]
+    BrokenChain(u64),
+}
+
+/// Check every record of a log written by [`AuditLog`].
+pub fn verify(reader: impl BufRead) -> Result<Verified, VerifyError> {
+    let mut expected_prev = GENESIS.to_owned();
+    let mut records = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index as u64 + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (seq, prev, hash, record) =
+            parse_record(&line).ok_or(VerifyError::Malformed(line_no))?;
+        if hash_record(&prev, &record).ok().as_deref() != Some(hash.as_str()) {
+            return Err(VerifyError::Altered(line_no));
+        }
+        if seq != records + 1 {
+            return Err(VerifyError::Sequence {
+                line: line_no,
+                expected: records + 1,
+                found: seq,
+            });
+        }
+        if prev != expected_prev {
+            return Err(VerifyError::BrokenChain(line_no));
+        }
+
+        records = seq;
+        expected_prev = hash;
+    }
+
+    Ok(Verified {
+        records,
+        last_hash: expected_prev,
+    })
+}
+
+/// Split a line into its sequence number, previous hash, own hash and the
+/// hashed part of the record.
+fn parse_record(line: &str) -> Option<(u64, String, String, Value)> {
+    let mut fields: Map<String, Value> = serde_json::from_str(line).ok()?;
+    let hash = fields.remove("hash")?.as_str()?.to_owned();
+    let seq = fields.get("seq")?.as_u64()?;
+    let prev = fields.get("prev")?.as_str()?.to_owned();
+    Some((seq, prev, hash, Value::Object(fields)))
+}
+
+fn hash_record(prev: &str, record: &Value) -> serde_json::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(record)?);
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Sequence number and hash of the last record of an existing log.
+fn last_link(reader: impl BufRead) -> io::Result<(u64, String)> {
+    let mut link = (0, GENESIS.to_owned());
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (seq, _, hash, _) = parse_record(&line)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed audit record"))?;
+        link = (seq, hash);
+    }
+    Ok(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writer whose contents the test can read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn login(subject: &str) -> Login {
+        Login {
+            subject: subject.into(),
+            success: true,
+            method: "password".into(),
+            client_address: None,
+        }
+    }
+
+    async fn written_lines() -> Vec<String> {
+        let buffer = Buffer::default();
+        let log = AuditLog::new(buffer.clone());
+        for subject in ["alice", "bob", "carol", "dave"] {
+            log.record(&login(subject)).await.unwrap();
+        }
+        let contents = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        contents.lines().map(str::to_owned).collect()
+    }
+
+    fn verify_lines(lines: &[String]) -> Result<Verified, VerifyError> {
+        verify(lines.join("\n").as_bytes())
+    }
+
+    #[tokio::test]
+    async fn untouched_log_verifies() {
+        let lines = written_lines().await;
+        let verified = verify_lines(&lines).unwrap();
+        assert_eq!(verified.records, 4);
+        assert_eq!(verified.last_hash, parse_record(&lines[3]).unwrap().2,);
+    }
+
+    #[tokio::test]
+    async fn edited_record_is_detected() {
+        let mut lines = written_lines().await;
+        lines[1] = lines[1].replace("bob", "eve");
+        assert!(matches!(verify_lines(&lines), Err(VerifyError::Altered(2))));
+    }
+
+    #[tokio::test]
+    async fn deleted_record_is_detected() {
+        let mut lines = written_lines().await;
+        lines.remove(1);
+        assert!(matches!(
+            verify_lines(&lines),
+            Err(VerifyError::Sequence {
+                line: 2,
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reordered_records_are_detected() {
+        let mut lines = written_lines().await;
+        lines.swap(1, 2);
+        assert!(matches!(
+            verify_lines(&lines),
+            Err(VerifyError::Sequence { line: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn renumbered_record_is_detected() {
+        let mut lines = written_lines().await;
+        lines.remove(1);
+        let mut record: Map<String, Value> = serde_json::from_str(&lines[1]).unwrap();
+        record.insert("seq".into(), 2.into());
+        lines[1] = serde_json::to_string(&record).unwrap();
+        assert!(matches!(verify_lines(&lines), Err(VerifyError::Altered(2))));
+    }
+
+    #[tokio::test]
+    async fn reopened_log_continues_the_chain() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", uuid::Uuid::new_v4()));
+        AuditLog::open(&path)
+            .unwrap()
+            .record(&login("alice"))
+            .await
+            .unwrap();
+        AuditLog::open(&path)
+            .unwrap()
+            .record(&login("bob"))
+            .await
+            .unwrap();
+
+        let verified = verify(BufReader::new(File::open(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(verified.unwrap().records, 2);
+    }
+}
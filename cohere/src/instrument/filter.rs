@@ -0,0 +1,253 @@
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::{runtime::Handle, task::JoinHandle};
+use tracing_subscriber::{EnvFilter, filter::ParseError, reload};
+
+/// Longest a temporary filter may stay in place.
+pub const MAX_FILTER_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+struct LogFilter {
+    reload: Reload,
+    state: Mutex<FilterState>,
+}
+
+struct FilterState {
+    current: String,
+    /// Filter restored when a temporary one expires.
+    base: String,
+    /// Bumped on every change, so an expiry only restores the filter it
+    /// replaced.
+    generation: u64,
+    /// Timer restoring `base`, replaced whenever the filter changes.
+    expiry: Option<JoinHandle<()>>,
+}
+
+static LOG_FILTER: OnceCell<LogFilter> = OnceCell::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error("logging is not initialized")]
+    NotInitialized,
+    #[error("invalid filter: {0}")]
+    Invalid(#[from] ParseError),
+    #[error("failed to reload filter: {0}")]
+    Reload(#[from] reload::Error),
+    #[error("filter ttl exceeds {} seconds", MAX_FILTER_TTL.as_secs())]
+    TtlTooLong,
+    #[error("a temporary filter needs a tokio runtime")]
+    NoRuntime,
+}
+
+pub(super) fn install(
+    directives: String,
+    reload: impl Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync + 'static,
+) {
+    let _ = LOG_FILTER.set(LogFilter {
+        reload: Box::new(reload),
+        state: Mutex::new(FilterState {
+            current: directives.clone(),
+            base: directives,
+            generation: 0,
+            expiry: None,
+        }),
+    });
+}
+
+/// Directives of the filter currently applied to the logs.
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER
+        .get()
+        .map(|filter| filter.state.lock().current.clone())
+}
+
+/// Replace the filter applied to the logs, such as `info,cohere=debug`.
+///
+/// With a `ttl`, at most [`MAX_FILTER_TTL`], the filter in place before is
+/// restored once it elapses, unless the filter was changed again in the
+/// meantime. Setting a temporary filter while another one is active keeps
+/// the original to restore. Temporary filters must be set from within a
+/// tokio runtime, which runs the timer.
+pub fn set_log_filter(directives: &str, ttl: Option<Duration>) -> Result<(), FilterError> {
+    let filter = LOG_FILTER.get().ok_or(FilterError::NotInitialized)?;
+    let env_filter = EnvFilter::try_new(directives)?;
+    let runtime = match ttl {
+        Some(ttl) if ttl > MAX_FILTER_TTL => return Err(FilterError::TtlTooLong),
+        Some(_) => Some(Handle::try_current().map_err(|_| FilterError::NoRuntime)?),
+        None => None,
+    };
+
+    let mut state = filter.state.lock();
+    (filter.reload)(env_filter)?;
+    state.current = directives.to_owned();
+    state.generation += 1;
+    if let Some(expiry) = state.expiry.take() {
+        expiry.abort();
+    }
+
+    match (ttl, runtime) {
+        (Some(ttl), Some(runtime)) => {
+            let generation = state.generation;
+            state.expiry = Some(runtime.spawn(async move {
+                tokio::time::sleep(ttl).await;
+                restore(generation);
+            }));
+        }
+        _ => state.base = directives.to_owned(),
+    }
+    Ok(())
+}
+
+fn restore(generation: u64) {
+    let Some(filter) = LOG_FILTER.get() else {
+        return;
+    };
+    let mut state = filter.state.lock();
+    if state.generation != generation {
+        return;
+    }
+
+    let restored = EnvFilter::try_new(&state.base)
+        .map_err(FilterError::from)
+        .and_then(|env_filter| (filter.reload)(env_filter).map_err(FilterError::from));
+    match restored {
+        Ok(()) => {
+            state.current = state.base.clone();
+            state.generation += 1;
+            state.expiry = None;
+            tracing::info!(filter = %state.base, "restored log filter");
+        }
+        Err(err) => tracing::error!(error.message = %err, "failed to restore log filter"),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+    static LOCK: AsyncMutex<()> = AsyncMutex::const_new(());
+    /// Filters passed to the reload hook, in order.
+    static APPLIED: StdMutex<Vec<String>> = StdMutex::new(Vec::new());
+
+    /// Take the process-wide filter for one test, with `base` in place and
+    /// a reload hook that records filters instead of applying them.
+    pub(crate) async fn isolated(base: &str) -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock().await;
+        install(base.to_owned(), |filter| {
+            APPLIED.lock().unwrap().push(filter.to_string());
+            Ok(())
+        });
+        let mut state = LOG_FILTER.get().unwrap().state.lock();
+        if let Some(expiry) = state.expiry.take() {
+            expiry.abort();
+        }
+        state.current = base.to_owned();
+        state.base = base.to_owned();
+        state.generation += 1;
+        APPLIED.lock().unwrap().clear();
+        guard
+    }
+
+    pub(crate) fn applied() -> Vec<String> {
+        APPLIED.lock().unwrap().clone()
+    }
+
+    async fn wait_for(directives: &str) {
+        for _ in 0..100 {
+            if log_filter().as_deref() == Some(directives) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("filter never became {directives:?}");
+    }
+
+    #[tokio::test]
+    async fn permanent_filter_becomes_the_base() {
+        let _guard = isolated("info").await;
+        set_log_filter("debug", None).unwrap();
+        assert_eq!(log_filter().as_deref(), Some("debug"));
+        assert_eq!(applied(), ["debug"]);
+
+        set_log_filter("trace", Some(Duration::from_millis(20))).unwrap();
+        wait_for("debug").await;
+        assert_eq!(applied(), ["debug", "trace", "debug"]);
+    }
+
+    #[tokio::test]
+    async fn temporary_filter_expires() {
+        let _guard = isolated("info").await;
+        set_log_filter("debug", Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(log_filter().as_deref(), Some("debug"));
+        wait_for("info").await;
+        assert_eq!(applied(), ["debug", "info"]);
+    }
+
+    #[tokio::test]
+    async fn later_change_cancels_the_expiry() {
+        let _guard = isolated("info").await;
+        set_log_filter("debug", Some(Duration::from_millis(20))).unwrap();
+        set_log_filter("warn", None).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(log_filter().as_deref(), Some("warn"));
+        assert_eq!(applied(), ["debug", "warn"]);
+    }
+
+    #[tokio::test]
+    async fn stacked_temporary_filters_restore_the_original() {
+        let _guard = isolated("info").await;
+        set_log_filter("debug", Some(Duration::from_millis(20))).unwrap();
+        set_log_filter("trace", Some(Duration::from_millis(150))).unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(log_filter().as_deref(), Some("trace"));
+        wait_for("info").await;
+        assert_eq!(applied(), ["debug", "trace", "info"]);
+    }
+
+    #[tokio::test]
+    async fn stale_generation_is_not_restored() {
+        let _guard = isolated("info").await;
+        set_log_filter("debug", None).unwrap();
+        let generation = LOG_FILTER.get().unwrap().state.lock().generation;
+        restore(generation - 1);
+        assert_eq!(log_filter().as_deref(), Some("debug"));
+
+        set_log_filter("trace", Some(MAX_FILTER_TTL)).unwrap();
+        restore(generation + 1);
+        assert_eq!(log_filter().as_deref(), Some("debug"));
+        assert_eq!(applied(), ["debug", "trace", "debug"]);
+    }
+
+    #[tokio::test]
+    async fn ttl_is_capped() {
+        let _guard = isolated("info").await;
+        let too_long = MAX_FILTER_TTL + Duration::from_secs(1);
+        assert!(matches!(
+            set_log_filter("debug", Some(too_long)),
+            Err(FilterError::TtlTooLong)
+        ));
+        set_log_filter("debug", Some(MAX_FILTER_TTL)).unwrap();
+        assert_eq!(log_filter().as_deref(), Some("debug"));
+    }
+
+    #[tokio::test]
+    async fn rejected_filters_leave_the_current_one() {
+        let _guard = isolated("info").await;
+        assert!(matches!(
+            set_log_filter("cohere=loud", None),
+            Err(FilterError::Invalid(_))
+        ));
+        let no_runtime = std::thread::spawn(|| {
+            set_log_filter("debug", Some(Duration::from_secs(1))).unwrap_err()
+        })
+        .join()
+        .unwrap();
+        assert!(matches!(no_runtime, FilterError::NoRuntime));
+        assert_eq!(log_filter().as_deref(), Some("info"));
+        assert!(applied().is_empty());
+    }
+}
@@ -1,23 +1,103 @@
-use std::io::Write;
+use std::sync::Arc;
 
 use rustc_hash::FxHashMap;
-use serde_json::Value;
-use tracing::field::Visit;
+use serde_json::{Map, Number, Value, json};
+use tracing::{field::Visit, span};
 use tracing_opentelemetry::OtelData;
 use tracing_subscriber::Layer;
 
-pub struct LogLayer;
+use super::sink::Writer;
+
+/// Fields recorded on a span, kept in its extensions so events inside it
+/// can be logged with them.
+struct SpanFields(FxHashMap<String, Value>);
+
+pub struct LogLayer {
+    /// Log every enclosing span under `spans` as `{"name": .., "fields": {..}}`.
+    span_list: bool,
+    /// Turn dotted field names into nested objects.
+    nested: bool,
+    writer: Arc<Writer>,
+}
+
+impl LogLayer {
+    pub fn new(span_list: bool, nested: bool, writer: Arc<Writer>) -> Self {
+        LogLayer {
+            span_list,
+            nested,
+            writer,
+        }
+    }
+}
 
 impl<S> Layer<S> for LogLayer
 where
     S: tracing::Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
 {
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = Visitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.attrs));
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut visitor = Visitor {
+                attrs: std::mem::take(fields),
+            };
+            values.record(&mut visitor);
+            *fields = visitor.attrs;
+        }
+    }
+
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let mut visitor = Visitor::default();
         event.record(&mut visitor);
 
-        let lvl = event.metadata().level();
+        let mut attributes = FxHashMap::default();
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let Some(SpanFields(fields)) = extensions.get::<SpanFields>() else {
+                    continue;
+                };
+                // `otel.*` fields configure the exported span, not the request.
+                let fields = fields.iter().filter(|(key, _)| !key.starts_with("otel."));
+                if self.span_list {
+                    let fields = layout(
+                        fields.clone().map(|(k, v)| (k.clone(), v.clone())),
+                        self.nested,
+                    );
+                    spans.push(json!({ "name": span.name(), "fields": fields }));
+                }
+                attributes.extend(fields.map(|(k, v)| (k.clone(), v.clone())));
+            }
+        }
+        // Event fields win over span fields of the same name.
+        attributes.extend(visitor.attrs.drain());
+        visitor.attrs = attributes;
 
+        let metadata = event.metadata();
+        let mut trace_id = String::new();
+        let mut span_id = String::new();
         if let Some(span) = ctx.event_span(event) {
             let opt_span_id = span
                 .extensions()
@@ -30,12 +110,12 @@ where
                     .and_then(|otd| otd.builder.trace_id)
             });
 
-            if let Some((trace_id, span_id)) = opt_trace_id.zip(opt_span_id) {
-                visitor.print(lvl, trace_id.to_string(), span_id.to_string());
-                return;
+            if let Some((trace, span)) = opt_trace_id.zip(opt_span_id) {
+                trace_id = trace.to_string();
+                span_id = span.to_string();
             }
         }
-        visitor.print(lvl, "".into(), "".into());
+        visitor.print(self, metadata, trace_id, span_id, spans);
     }
 }
 
@@ -71,6 +151,39 @@ impl Visit for Visitor {
         self.attrs
             .insert(field.name().to_string(), Value::Bool(value));
     }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        // JSON has no NaN or infinity.
+        let value = Number::from_f64(value).map_or_else(|| value.to_string().into(), Value::Number);
+        self.attrs.insert(field.name().to_string(), value);
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        let value = i64::try_from(value).map_or_else(|_| value.to_string().into(), Value::from);
+        self.attrs.insert(field.name().to_string(), value);
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        let value = u64::try_from(value).map_or_else(|_| value.to_string().into(), Value::from);
+        self.attrs.insert(field.name().to_string(), value);
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        let chain: Vec<Value> = std::iter::successors(value.source(), |err| err.source())
+            .map(|err| err.to_string().into())
+            .collect();
+        self.attrs.insert(
+            field.name().to_string(),
+            json!({
+                "message": value.to_string(),
+                "chain": chain,
+            }),
+        );
+    }
 }
 
 #[derive(serde::Serialize)]
@@ -85,13 +198,29 @@ struct Event {
     trace_id: String,
     #[serde(skip_serializing_if = "String::is_empty")]
     span_id: String,
+    target: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    spans: Vec<Value>,
     #[serde(rename = "attrs")]
-    #[serde(skip_serializing_if = "FxHashMap::is_empty")]
-    attributes: FxHashMap<String, Value>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    attributes: Map<String, Value>,
 }
 
 impl Visitor {
-    fn print(mut self, level: &tracing::Level, trace_id: String, span_id: String) {
+    fn print(
+        mut self,
+        layer: &LogLayer,
+        metadata: &'static tracing::Metadata<'static>,
+        trace_id: String,
+        span_id: String,
+        spans: Vec<Value>,
+    ) {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
@@ -101,22 +230,310 @@ impl Visitor {
             .remove("message")
             .and_then(|v| v.as_str().map(String::from))
             .unwrap_or_else(|| "-".into());
-        let level = level.to_string().to_lowercase();
+        let level = metadata.level().to_string().to_lowercase();
         let event = Event {
             message,
             level,
             timestamp,
             trace_id,
             span_id,
-            attributes: self.attrs,
+            target: metadata.target(),
+            module: metadata.module_path(),
+            file: metadata.file(),
+            line: metadata.line(),
+            spans,
+            attributes: layout(self.attrs, layer.nested),
         };
         let mut buffer = Vec::with_capacity(512);
         if serde_json::to_writer(&mut buffer, &event).is_ok() {
-            let mut stdout = std::io::stdout().lock();
-            let _ = stdout.write_all(&buffer);
-            let _ = stdout.write_all(b"\n");
-        } else {
-            println!("{}", serde_json::to_string(&event).unwrap());
+            buffer.push(b'\n');
+            layer.writer.write_line(buffer);
+        }
+    }
+}
+
+/// Collect fields into an object, splitting dotted names such as
+/// `http.response.status_code` into nested objects when `nested` is set. A
+/// name that clashes with a value already at one of its parents is kept
+/// flat.
+fn layout(fields: impl IntoIterator<Item = (String, Value)>, nested: bool) -> Map<String, Value> {
+    if !nested {
+        return fields.into_iter().collect();
+    }
+
+    // Shorter names first, so `a` is placed before `a.b` clashes with it.
+    let mut fields: Vec<_> = fields.into_iter().collect();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut root = Map::new();
+    for (name, value) in fields {
+        if let Some(value) = insert_path(&mut root, &name, value) {
+            root.insert(name, value);
+        }
+    }
+    root
+}
+
+/// Place `value` at the dotted `name`, returning it if the path is taken.
+fn insert_path(map: &mut Map<String, Value>, name: &str, value: Value) -> Option<Value> {
+    let (parents, leaf) = match name.rsplit_once('.') {
+        Some((parents, leaf)) => (Some(parents), leaf),
+        None => (None, name),
+    };
+
+    let mut current = map;
+    for part in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        let entry = current
+            .entry(part.to_owned())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let Value::Object(next) = entry else {
+            return Some(value);
+        };
+        current = next;
+    }
+    if current.contains_key(leaf) {
+        return Some(value);
+    }
+    current.insert(leaf.to_owned(), value);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::{LogSink, RollingFile};
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    /// Log lines written while `f` runs under a [`LogLayer`].
+    fn capture(span_list: bool, nested: bool, f: impl FnOnce()) -> Vec<Value> {
+        let directory =
+            std::env::temp_dir().join(format!("cohere-logging-{}", uuid::Uuid::new_v4()));
+        let writer = LogSink::File(RollingFile::new(&directory, "app"))
+            .open()
+            .unwrap();
+        let subscriber =
+            Registry::default().with(LogLayer::new(span_list, nested, Arc::new(writer)));
+        tracing::subscriber::with_default(subscriber, f);
+
+        let written = std::fs::read_to_string(directory.join("app.log")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn event_carries_its_metadata() {
+        let mut line = 0;
+        let lines = capture(false, false, || {
+            tracing::warn!(target: "cohere::audit", user = "alice", "signed in");
+            line = line!() - 1;
+        });
+
+        let [event] = &lines[..] else {
+            panic!("expected one line, got {lines:?}");
+        };
+        assert_eq!(event["msg"], "signed in");
+        assert_eq!(event["lvl"], "warn");
+        assert!(event["ts"].as_i64().unwrap() > 0);
+        assert_eq!(event["target"], "cohere::audit");
+        assert_eq!(event["module"], module_path!());
+        assert_eq!(event["file"], file!());
+        assert_eq!(event["line"], line);
+        assert_eq!(event["attrs"], json!({"user": "alice"}));
+        // Without an OpenTelemetry layer there is no trace to link to.
+        assert!(event.get("trace_id").is_none());
+        assert!(event.get("spans").is_none());
+    }
+
+    #[test]
+    fn span_fields_are_merged_into_attrs() {
+        let lines = capture(false, false, || {
+            let outer = tracing::info_span!(
+                "request",
+                user = "alice",
+                route = "/",
+                otel.name = "GET /",
+                otel.kind = "server",
+                status = tracing::field::Empty,
+            );
+            let _outer = outer.enter();
+            outer.record("status", 200);
+            let _inner = tracing::info_span!("query", route = "/users", rows = 3).entered();
+            tracing::info!(rows = 5, "done");
+        });
+
+        assert_eq!(
+            lines[0]["attrs"],
+            json!({
+                "user": "alice",
+                // Inner spans win over outer ones, and the event over both.
+                "route": "/users",
+                "rows": 5,
+                "status": 200,
+            })
+        );
+    }
+
+    #[test]
+    fn span_list_keeps_fields_per_span() {
+        let lines = capture(true, false, || {
+            let _outer =
+                tracing::info_span!("request", user = "alice", otel.name = "GET /").entered();
+            let _inner = tracing::info_span!("query").entered();
+            tracing::info!(rows = 5, "done");
+        });
+
+        assert_eq!(
+            lines[0]["spans"],
+            json!([
+                {"name": "request", "fields": {"user": "alice"}},
+                {"name": "query", "fields": {}},
+            ])
+        );
+        assert_eq!(lines[0]["attrs"], json!({"user": "alice", "rows": 5}));
+    }
+
+    #[test]
+    fn events_outside_spans_have_no_span_list() {
+        let lines = capture(true, false, || tracing::info!("idle"));
+        assert!(lines[0].get("spans").is_none());
+        assert!(lines[0].get("attrs").is_none());
+    }
+
+    fn fields(names: &[(&str, Value)]) -> Vec<(String, Value)> {
+        names
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn dotted_names_are_nested() {
+        let laid_out = layout(
+            fields(&[
+                ("http.response.status_code", json!(200)),
+                ("http.request.method", json!("GET")),
+                ("a.b.c.d.e", json!(true)),
+                ("user", json!("alice")),
+            ]),
+            true,
+        );
+        assert_eq!(
+            Value::Object(laid_out),
+            json!({
+                "http": {"request": {"method": "GET"}, "response": {"status_code": 200}},
+                "a": {"b": {"c": {"d": {"e": true}}}},
+                "user": "alice",
+            })
+        );
+    }
+
+    #[test]
+    fn clashing_names_stay_flat() {
+        // Whichever order they arrive in, the parent keeps its place.
+        for names in [["a", "a.b", "a.b.c"], ["a.b.c", "a.b", "a"]] {
+            let laid_out = layout(
+                names.iter().map(|name| (name.to_string(), json!(name))),
+                true,
+            );
+            assert_eq!(
+                Value::Object(laid_out),
+                json!({"a": "a", "a.b": "a.b", "a.b.c": "a.b.c"})
+            );
         }
+
+        let laid_out = layout(
+            fields(&[("a.b", json!(1)), ("a.b.c", json!(2)), ("a.d", json!(3))]),
+            true,
+        );
+        assert_eq!(
+            Value::Object(laid_out),
+            json!({"a": {"b": 1, "d": 3}, "a.b.c": 2})
+        );
+    }
+
+    #[test]
+    fn flat_layout_keeps_dotted_names() {
+        let laid_out = layout(fields(&[("a", json!(1)), ("a.b", json!(2))]), false);
+        assert_eq!(Value::Object(laid_out), json!({"a": 1, "a.b": 2}));
+    }
+
+    #[test]
+    fn nested_layout_applies_to_span_fields() {
+        let lines = capture(true, true, || {
+            let _span = tracing::info_span!("request", http.method = "GET").entered();
+            tracing::info!(http.status_code = 200, "done");
+        });
+        assert_eq!(
+            lines[0]["spans"],
+            json!([{"name": "request", "fields": {"http": {"method": "GET"}}}])
+        );
+        assert_eq!(
+            lines[0]["attrs"],
+            json!({"http": {"method": "GET", "status_code": 200}})
+        );
+    }
+
+    #[test]
+    fn values_json_cannot_hold_become_strings() {
+        let lines = capture(false, false, || {
+            tracing::info!(
+                nan = f64::NAN,
+                inf = f64::INFINITY,
+                neg_inf = f64::NEG_INFINITY,
+                ratio = 0.5,
+                small = -5i128,
+                huge = i128::MIN,
+                count = 7u128,
+                total = u128::MAX,
+                "values"
+            );
+        });
+        assert_eq!(
+            lines[0]["attrs"],
+            json!({
+                "nan": "NaN",
+                "inf": "inf",
+                "neg_inf": "-inf",
+                "ratio": 0.5,
+                "small": -5,
+                "huge": i128::MIN.to_string(),
+                "count": 7,
+                "total": u128::MAX.to_string(),
+            })
+        );
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("request failed")]
+    struct Request(#[source] Connect);
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("connect failed")]
+    struct Connect(#[source] std::io::Error);
+
+    #[test]
+    fn errors_are_logged_with_their_sources() {
+        let err = Request(Connect(std::io::Error::other("connection refused")));
+        let source = Connect(std::io::Error::other("timed out"));
+        let lines = capture(false, false, || {
+            tracing::error!(
+                error = &err as &(dyn std::error::Error + 'static),
+                cause = &source as &(dyn std::error::Error + 'static),
+                "failed"
+            );
+        });
+        assert_eq!(
+            lines[0]["attrs"],
+            json!({
+                "error": {
+                    "message": "request failed",
+                    "chain": ["connect failed", "connection refused"],
+                },
+                "cause": {"message": "connect failed", "chain": ["timed out"]},
+            })
+        );
     }
 }
@@ -1,17 +1,146 @@
+pub mod audit;
+mod filter;
 mod logging;
+mod sink;
 mod tracing;
 
+use anyhow::Context as _;
 use opentelemetry_sdk::trace as sdktrace;
+use std::sync::Arc;
+
+use crate::env;
+
+pub use filter::{FilterError, MAX_FILTER_TTL, log_filter, set_log_filter};
+pub use sink::{LogSink, RollingFile, Rotation};
+
+#[cfg(test)]
+pub(crate) use filter::tests as filter_tests;
+
+/// Levels used when neither the config nor the environment sets a filter.
+const LOCAL_FILTER: &str = "debug,hyper=info,h2=info,reqwest=info,rustls=info";
+const DEFAULT_FILTER: &str = "info";
 
 pub struct InstrumentGuard {
     tracer_provider: sdktrace::SdkTracerProvider,
+    writer: Arc<sink::Writer>,
+}
+
+impl InstrumentGuard {
+    /// Log lines lost because a [`LogSink::NonBlocking`] writer fell behind
+    /// or stopped, or a log file could not be written. Nothing is reported
+    /// on its own, so read this before dropping the guard to export it.
+    pub fn dropped_log_lines(&self) -> u64 {
+        self.writer.dropped()
+    }
+}
+
+/// Settings for [`init_with`].
+///
+/// Filters use `RUST_LOG` directives, such as `info,cohere=debug,hyper=warn`.
+/// The log filter defaults to `LOG_LEVEL`, then `RUST_LOG`, and the trace
+/// filter to `TRACE_LEVEL`. Without any of them, everything at `info` and
+/// above is kept, or at `debug` when [`env::parse`] already loaded
+/// [`Env::Local`](env::Env::Local). Before the environment is loaded the
+/// default does not assume it is local.
+#[derive(Clone, Debug)]
+pub struct InstrumentConfig {
+    org: String,
+    project: String,
+    log_filter: Option<String>,
+    trace_filter: Option<String>,
+    span_list: bool,
+    nested_attributes: bool,
+    log_sink: LogSink,
+}
+
+impl InstrumentConfig {
+    pub fn new(org: impl Into<String>, project: impl Into<String>) -> Self {
+        InstrumentConfig {
+            org: org.into(),
+            project: project.into(),
+            log_filter: None,
+            trace_filter: None,
+            span_list: false,
+            nested_attributes: false,
+            log_sink: LogSink::Stdout,
+        }
+    }
+
+    /// Which events are written to the logs.
+    pub fn with_log_filter(mut self, directives: impl Into<String>) -> Self {
+        self.log_filter = Some(directives.into());
+        self
+    }
+
+    /// Which spans and events are exported to OpenTelemetry.
+    pub fn with_trace_filter(mut self, directives: impl Into<String>) -> Self {
+        self.trace_filter = Some(directives.into());
+        self
+    }
+
+    /// Log the name and fields of every span enclosing an event under
+    /// `spans`, as `{"name": .., "fields": {..}}`. Span fields are always
+    /// merged into `attrs`; the list shows which span each one came from.
+    pub fn with_span_list(mut self, span_list: bool) -> Self {
+        self.span_list = span_list;
+        self
+    }
+
+    /// Log dotted field names such as `http.response.status_code` as nested
+    /// objects rather than flat keys.
+    pub fn with_nested_attributes(mut self, nested: bool) -> Self {
+        self.nested_attributes = nested;
+        self
+    }
+
+    /// Where log lines are written, stdout by default.
+    pub fn with_log_sink(mut self, sink: LogSink) -> Self {
+        self.log_sink = sink;
+        self
+    }
+
+    fn log_directives(&self) -> String {
+        self.log_filter
+            .clone()
+            .or_else(|| env_directives("LOG_LEVEL"))
+            .or_else(|| env_directives("RUST_LOG"))
+            .unwrap_or_else(default_directives)
+    }
+
+    fn trace_directives(&self) -> String {
+        self.trace_filter
+            .clone()
+            .or_else(|| env_directives("TRACE_LEVEL"))
+            .unwrap_or_else(default_directives)
+    }
+}
+
+fn env_directives(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn default_directives() -> String {
+    if env::is_local() {
+        LOCAL_FILTER.into()
+    } else {
+        DEFAULT_FILTER.into()
+    }
 }
 
 /// Return a guard that will flush instrumentation data when it is dropped.
 pub fn init(org: &str, project: &str) -> anyhow::Result<InstrumentGuard> {
-    let provider = tracing::init(org, project)?;
+    init_with(InstrumentConfig::new(org, project))
+}
+
+/// Like [`init`], with control over which events are logged and traced.
+pub fn init_with(config: InstrumentConfig) -> anyhow::Result<InstrumentGuard> {
+    let writer = Arc::new(config.log_sink.open().context("failed to open log sink")?);
+    let provider = tracing::init(&config, writer.clone())?;
     Ok(InstrumentGuard {
         tracer_provider: provider,
+        writer,
     })
 }
 
@@ -20,5 +149,6 @@ impl Drop for InstrumentGuard {
         if let Err(err) = self.tracer_provider.shutdown() {
             println!("Error shutting down tracer provider: {:?}", err);
         }
+        self.writer.flush();
     }
 }
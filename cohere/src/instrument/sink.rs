@@ -0,0 +1,458 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long dropping the [`InstrumentGuard`](super::InstrumentGuard) waits
+/// for a [`LogSink::NonBlocking`] writer to catch up.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where log lines are written.
+#[derive(Clone, Debug, Default)]
+pub enum LogSink {
+    #[default]
+    Stdout,
+    Stderr,
+    File(RollingFile),
+    /// Hand lines to a background thread writing to `sink`, so logging never
+    /// waits on I/O. Lines are dropped, and counted, while `capacity` lines
+    /// are already waiting. A `capacity` of 0 is treated as 1.
+    NonBlocking {
+        sink: Box<LogSink>,
+        capacity: usize,
+    },
+}
+
+impl LogSink {
+    pub fn non_blocking(sink: LogSink, capacity: usize) -> Self {
+        LogSink::NonBlocking {
+            sink: Box::new(sink),
+            capacity,
+        }
+    }
+
+    pub(super) fn open(&self) -> io::Result<Writer> {
+        self.open_counting(Arc::default())
+    }
+
+    /// Open a writer that adds the lines it loses to `dropped`, which is
+    /// shared with the writers it hands lines to.
+    fn open_counting(&self, dropped: Arc<AtomicU64>) -> io::Result<Writer> {
+        let output = match self {
+            LogSink::Stdout => Output::Stdout,
+            LogSink::Stderr => Output::Stderr,
+            LogSink::File(file) => Output::File(Mutex::new(RollingWriter::open(file.clone())?)),
+            LogSink::NonBlocking { sink, capacity } => {
+                let inner = sink.open_counting(dropped.clone())?;
+                // A zero capacity would make every send wait for the writer.
+                let (sender, receiver) = mpsc::sync_channel((*capacity).max(1));
+                thread::Builder::new()
+                    .name("cohere-log-writer".into())
+                    .spawn(move || write_lines(inner, receiver))?;
+                Output::NonBlocking(sender)
+            }
+        };
+        Ok(Writer { output, dropped })
+    }
+}
+
+/// When a [`RollingFile`] starts a new file, besides reaching its size limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Index of the UTC period `time` falls in.
+    fn period(self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86400,
+        }
+    }
+}
+
+/// A log file at `<directory>/<prefix>.log`. When it rotates, it is renamed
+/// to `<prefix>.<UTC timestamp>.log` and a new one is started.
+#[derive(Clone, Debug)]
+pub struct RollingFile {
+    directory: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+impl RollingFile {
+    pub fn new(directory: impl Into<PathBuf>, prefix: impl Into<String>) -> Self {
+        RollingFile {
+            directory: directory.into(),
+            prefix: prefix.into(),
+            rotation: Rotation::Never,
+            max_size: None,
+            max_files: None,
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Rotate before the file grows past `bytes`.
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Keep at most `count` rotated files, deleting the oldest.
+    pub fn with_max_files(mut self, count: usize) -> Self {
+        self.max_files = Some(count);
+        self
+    }
+
+    fn path(&self) -> PathBuf {
+        self.directory.join(format!("{}.log", self.prefix))
+    }
+}
+
+pub(super) struct Writer {
+    output: Output,
+    dropped: Arc<AtomicU64>,
+}
+
+enum Output {
+    Stdout,
+    Stderr,
+    File(Mutex<RollingWriter>),
+    NonBlocking(SyncSender<Message>),
+}
+
+pub(super) enum Message {
+    Line(Vec<u8>),
+    Flush(SyncSender<()>),
+}
+
+impl Writer {
+    /// Write `line`, which ends with a newline.
+    pub(super) fn write_line(&self, line: Vec<u8>) {
+        let written = match &self.output {
+            Output::Stdout => {
+                let _ = io::stdout().lock().write_all(&line);
+                true
+            }
+            Output::Stderr => {
+                let _ = io::stderr().lock().write_all(&line);
+                true
+            }
+            Output::File(file) => file.lock().write_line(&line).is_ok(),
+            // Once the writer thread is gone every line is lost, like a full
+            // queue.
+            Output::NonBlocking(sender) => sender.try_send(Message::Line(line)).is_ok(),
+        };
+        if !written {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lines lost since the writer was opened, because a
+    /// [`LogSink::NonBlocking`] queue was full or its thread had stopped, or
+    /// a log file could not be written.
+    pub(super) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn flush(&self) {
+        match &self.output {
+            Output::Stdout => {
+                let _ = io::stdout().flush();
+            }
+            Output::Stderr => {
+                let _ = io::stderr().flush();
+            }
+            Output::File(file) => {
+                let _ = file.lock().file.flush();
+            }
+            Output::NonBlocking(sender) => {
+                // A full queue is retried rather than waited on, so a stuck
+                // writer thread cannot hold the caller past the timeout.
+                let deadline = Instant::now() + FLUSH_TIMEOUT;
+                let (done, flushed) = mpsc::sync_channel(1);
+                let mut message = Message::Flush(done);
+                loop {
+                    match sender.try_send(message) {
+                        Ok(()) => break,
+                        Err(TrySendError::Full(returned)) if Instant::now() < deadline => {
+                            message = returned;
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                        Err(_) => return,
+                    }
+                }
+                let _ = flushed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+            }
+        }
+    }
+}
+
+fn write_lines(writer: Writer, receiver: Receiver<Message>) {
+    for message in receiver {
+        match message {
+            Message::Line(line) => writer.write_line(line),
+            Message::Flush(done) => {
+                writer.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+    writer.flush();
+}
+
+pub(super) struct RollingWriter {
+    config: RollingFile,
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+impl RollingWriter {
+    fn open(config: RollingFile) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        let path = config.path();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left by a previous run belongs to the period it was last
+        // written in.
+        let period = config
+            .rotation
+            .period(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+
+        Ok(RollingWriter {
+            config,
+            file,
+            size: metadata.len(),
+            period,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let period = self.config.rotation.period(SystemTime::now());
+        let full = self
+            .config
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        if (period != self.period && self.size > 0) || full {
+            self.rotate()?;
+        }
+        self.period = period;
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let timestamp = DateTime::<Utc>::from(SystemTime::now()).format("%Y%m%dT%H%M%S%.3fZ");
+        let mut rotated = self
+            .config
+            .directory
+            .join(format!("{}.{}.log", self.config.prefix, timestamp));
+        let mut attempt = 1;
+        while rotated.exists() {
+            rotated = self.config.directory.join(format!(
+                "{}.{}-{}.log",
+                self.config.prefix, timestamp, attempt
+            ));
+            attempt += 1;
+        }
+
+        let path = self.config.path();
+        fs::rename(&path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.size = 0;
+
+        if let Some(max_files) = self.config.max_files {
+            self.remove_old_files(max_files)?;
+        }
+        Ok(())
+    }
+
+    fn remove_old_files(&self, max_files: usize) -> io::Result<()> {
+        let current = format!("{}.log", self.config.prefix);
+        let prefix = format!("{}.", self.config.prefix);
+        let mut rotated: Vec<(Option<SystemTime>, PathBuf)> = fs::read_dir(&self.config.directory)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name != current
+                    && name.ends_with(".log")
+                    && name
+                        .strip_prefix(&prefix)
+                        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+            })
+            .map(|entry| {
+                let modified = entry.metadata().and_then(|m| m.modified()).ok();
+                (modified, entry.path())
+            })
+            .collect();
+        // Oldest first. Names break ties, since they start with the time the
+        // file was rotated.
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(max_files);
+        for (_, path) in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("cohere-sink-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// Names of the rotated files in `directory`, oldest first, with their
+    /// contents.
+    fn rotated_files(directory: &PathBuf) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "app.log")
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn zero_capacity_queues_a_line() {
+        let directory = temp_dir();
+        let sink = LogSink::File(RollingFile::new(&directory, "app"));
+        let writer = LogSink::non_blocking(sink, 0).open().unwrap();
+        writer.write_line(b"queued\n".to_vec());
+        writer.flush();
+        assert_eq!(writer.dropped(), 0);
+        assert_eq!(
+            fs::read_to_string(directory.join("app.log")).unwrap(),
+            "queued\n"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn flush_waits_for_queued_lines() {
+        let directory = temp_dir();
+        let sink = LogSink::File(RollingFile::new(&directory, "app"));
+        let writer = LogSink::non_blocking(sink, 2).open().unwrap();
+        for _ in 0..100 {
+            writer.write_line(b"line\n".to_vec());
+        }
+        writer.flush();
+        let written = fs::read_to_string(directory.join("app.log")).unwrap();
+        assert_eq!(written.lines().count() as u64 + writer.dropped(), 100);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn full_file_is_rotated() {
+        let directory = temp_dir();
+        let writer = LogSink::File(RollingFile::new(&directory, "app").with_max_size(10))
+            .open()
+            .unwrap();
+        // A line longer than the limit still goes to an empty file.
+        for line in ["first\n", "second line\n", "third\n"] {
+            writer.write_line(line.as_bytes().to_vec());
+            // Rotated names differ in their millisecond timestamp.
+            thread::sleep(Duration::from_millis(5));
+        }
+        writer.flush();
+
+        assert_eq!(
+            fs::read_to_string(directory.join("app.log")).unwrap(),
+            "third\n"
+        );
+        let rotated = rotated_files(&directory);
+        let contents: Vec<&str> = rotated.iter().map(|(_, c)| c.as_str()).collect();
+        assert_eq!(contents, ["first\n", "second line\n"]);
+        for (name, _) in &rotated {
+            let timestamp = name
+                .strip_prefix("app.")
+                .and_then(|rest| rest.strip_suffix(".log"))
+                .unwrap();
+            NaiveDateTime::parse_from_str(timestamp, "%Y%m%dT%H%M%S%.3fZ").unwrap();
+        }
+        assert_eq!(writer.dropped(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn oldest_rotated_files_are_deleted() {
+        let directory = temp_dir();
+        let writer = LogSink::File(
+            RollingFile::new(&directory, "app")
+                .with_max_size(1)
+                .with_max_files(2),
+        )
+        .open()
+        .unwrap();
+        // Unrelated files in the directory are left alone.
+        fs::write(directory.join("app.keep.log"), "other\n").unwrap();
+        for line in ["1\n", "2\n", "3\n", "4\n", "5\n"] {
+            writer.write_line(line.as_bytes().to_vec());
+            // Distinct modification times, which order the rotated files.
+            thread::sleep(Duration::from_millis(20));
+        }
+        writer.flush();
+
+        assert_eq!(
+            fs::read_to_string(directory.join("app.log")).unwrap(),
+            "5\n"
+        );
+        let rotated = rotated_files(&directory);
+        let contents: Vec<&str> = rotated.iter().map(|(_, c)| c.as_str()).collect();
+        assert_eq!(contents, ["3\n", "4\n", "other\n"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn failed_file_writes_are_counted() {
+        let directory = temp_dir();
+        let writer = LogSink::File(RollingFile::new(&directory, "app").with_max_size(1))
+            .open()
+            .unwrap();
+        writer.write_line(b"first\n".to_vec());
+        // Rotating needs the directory, which is gone.
+        fs::remove_dir_all(&directory).unwrap();
+        writer.write_line(b"second\n".to_vec());
+        assert_eq!(writer.dropped(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::Context as _;
 use opentelemetry::{
     Context, KeyValue,
     baggage::BaggageExt,
@@ -12,11 +13,21 @@ use opentelemetry_sdk::{
     trace::{SdkTracerProvider, Span, SpanProcessor},
 };
 use opentelemetry_stdout::SpanExporter;
-use tracing_subscriber::{Registry, layer::SubscriberExt};
+use std::sync::Arc;
+use tracing_subscriber::{EnvFilter, Layer, Registry, layer::SubscriberExt, reload};
 
-use super::logging::LogLayer;
+use super::{InstrumentConfig, filter, logging::LogLayer, sink::Writer};
+
+pub fn init(config: &InstrumentConfig, writer: Arc<Writer>) -> anyhow::Result<SdkTracerProvider> {
+    let (org, project) = (&config.org, &config.project);
+    let log_directives = config.log_directives();
+    let log_filter = EnvFilter::try_new(&log_directives)
+        .with_context(|| format!("invalid log filter {:?}", log_directives))?;
+    let (log_filter, handle) = reload::Layer::new(log_filter);
+    let trace_directives = config.trace_directives();
+    let trace_filter = EnvFilter::try_new(&trace_directives)
+        .with_context(|| format!("invalid trace filter {:?}", trace_directives))?;
 
-pub fn init(org: &str, project: &str) -> anyhow::Result<SdkTracerProvider> {
     let composite_propagator = TextMapCompositePropagator::new(vec![
         Box::new(BaggagePropagator::new()),
         Box::new(TraceContextPropagator::new()),
@@ -33,12 +44,16 @@ pub fn init(org: &str, project: &str) -> anyhow::Result<SdkTracerProvider> {
         )
         .build();
 
-    let telemetry =
-        tracing_opentelemetry::layer().with_tracer(provider.tracer(format!("{}/{}", org, project)));
+    let telemetry = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(format!("{}/{}", org, project)))
+        .with_filter(trace_filter);
 
-    let subscriber = Registry::default().with(telemetry).with(LogLayer);
+    let subscriber = Registry::default().with(telemetry).with(
+        LogLayer::new(config.span_list, config.nested_attributes, writer).with_filter(log_filter),
+    );
 
     tracing::subscriber::set_global_default(subscriber)?;
+    filter::install(log_directives, move |filter| handle.reload(filter));
 
     global::set_tracer_provider(provider.clone());
     Ok(provider)
@@ -3,3 +3,4 @@ pub mod env;
 pub mod instrument;
 pub mod secure;
 pub mod server;
+mod time;
@@ -0,0 +1,238 @@
+use base32::Alphabet::Rfc4648;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{future::Future, time::Duration};
+
+use super::{SecureError, random::fill_random};
+use crate::time::now;
+
+pub const LIVE_PREFIX: &str = "ck_live";
+pub const TEST_PREFIX: &str = "ck_test";
+
+const KEY_BYTES: usize = 20;
+
+/// Everything known about an issued key. Only the SHA-256 of the key is kept,
+/// so a leaked store cannot be used to call the services.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKeyRecord {
+    /// Identifies the caller, shared by all keys rotated from the same one.
+    pub id: String,
+    /// Hex encoded SHA-256 of the full key.
+    pub hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl ApiKeyRecord {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| now() >= at)
+    }
+}
+
+/// Storage for API key records, looked up by key hash.
+pub trait ApiKeyStore: Send + Sync + 'static {
+    fn get(
+        &self,
+        hash: &str,
+    ) -> impl Future<Output = Result<Option<ApiKeyRecord>, SecureError>> + Send;
+
+    fn put(&self, record: ApiKeyRecord) -> impl Future<Output = Result<(), SecureError>> + Send;
+
+    fn remove(&self, hash: &str) -> impl Future<Output = Result<(), SecureError>> + Send;
+}
+
+/// [`ApiKeyStore`] holding records in memory, such as keys loaded from
+/// configuration at startup. Keys added at runtime are lost on restart.
+#[derive(Default)]
+pub struct MemoryApiKeyStore {
+    records: DashMap<String, ApiKeyRecord>,
+}
+
+impl MemoryApiKeyStore {
+    pub fn new() -> Self {
+        MemoryApiKeyStore::default()
+    }
+}
+
+impl ApiKeyStore for MemoryApiKeyStore {
+    async fn get(&self, hash: &str) -> Result<Option<ApiKeyRecord>, SecureError> {
+        Ok(self.records.get(hash).map(|r| r.clone()))
+    }
+
+    async fn put(&self, record: ApiKeyRecord) -> Result<(), SecureError> {
+        self.records.insert(record.hash.clone(), record);
+        Ok(())
+    }
+
+    async fn remove(&self, hash: &str) -> Result<(), SecureError> {
+        self.records.remove(hash);
+        Ok(())
+    }
+}
+
+/// Generate a key such as `ck_live_...` for the caller `id`. The plaintext key
+/// is returned once; only the record should be stored.
+pub fn generate_api_key(
+    prefix: &str,
+    id: impl Into<String>,
+    scopes: Vec<String>,
+    ttl: Option<Duration>,
+) -> Result<(String, ApiKeyRecord), SecureError> {
+    let mut random = [0u8; KEY_BYTES];
+    fill_random(&mut random)?;
+
+    let key = format!(
+        "{}_{}",
+        prefix,
+        base32::encode(Rfc4648 { padding: false }, &random).to_lowercase()
+    );
+    let created_at = now();
+    let record = ApiKeyRecord {
+        id: id.into(),
+        hash: hash_api_key(&key),
+        scopes,
+        created_at,
+        expires_at: ttl.map(|ttl| created_at.saturating_add(ttl.as_secs())),
+    };
+
+    Ok((key, record))
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Look up the key and check that it has not expired.
+pub async fn verify_api_key<S: ApiKeyStore>(
+    store: &S,
+    key: &str,
+) -> Result<ApiKeyRecord, SecureError> {
+    let record = store
+        .get(&hash_api_key(key))
+        .await?
+        .ok_or(SecureError::InvalidKey)?;
+    if record.is_expired() {
+        return Err(SecureError::Expired);
+    }
+    Ok(record)
+}
+
+/// Issue a replacement for `old` with the same id and scopes, and let the old
+/// key keep working for `grace` so callers can switch over.
+pub async fn rotate_api_key<S: ApiKeyStore>(
+    store: &S,
+    prefix: &str,
+    old: &ApiKeyRecord,
+    grace: Duration,
+) -> Result<(String, ApiKeyRecord), SecureError> {
+    let ttl = old
+        .expires_at
+        .map(|at| Duration::from_secs(at.saturating_sub(old.created_at)));
+    let (key, record) = generate_api_key(prefix, old.id.clone(), old.scopes.clone(), ttl)?;
+    store.put(record.clone()).await?;
+
+    let grace_until = now().saturating_add(grace.as_secs());
+    let mut old = old.clone();
+    old.expires_at = Some(old.expires_at.map_or(grace_until, |at| at.min(grace_until)));
+    store.put(old).await?;
+
+    Ok((key, record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn generated_keys_verify_by_hash() {
+        let store = MemoryApiKeyStore::new();
+        let (key, record) =
+            generate_api_key(LIVE_PREFIX, "svc", vec!["read".into()], None).unwrap();
+        let random = key.strip_prefix("ck_live_").unwrap();
+        assert_eq!(random.len(), 32);
+        assert!(
+            random
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        );
+        assert_eq!(record.hash, hash_api_key(&key));
+
+        store.put(record.clone()).await.unwrap();
+        assert_eq!(verify_api_key(&store, &key).await.unwrap(), record);
+        assert!(record.has_scope("read"));
+        assert!(!record.has_scope("write"));
+    }
+
+    #[tokio::test]
+    async fn prefix_is_part_of_the_key() {
+        let store = MemoryApiKeyStore::new();
+        let (key, record) = generate_api_key(TEST_PREFIX, "svc", vec![], None).unwrap();
+        store.put(record).await.unwrap();
+        assert!(key.starts_with("ck_test_"));
+
+        // The same random part under another prefix is a different key.
+        let live = key.replacen(TEST_PREFIX, LIVE_PREFIX, 1);
+        assert!(matches!(
+            verify_api_key(&store, &live).await,
+            Err(SecureError::InvalidKey)
+        ));
+        assert!(matches!(
+            verify_api_key(&store, "ck_test_unknown").await,
+            Err(SecureError::InvalidKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_rejected() {
+        let store = MemoryApiKeyStore::new();
+        let (key, mut record) = generate_api_key(LIVE_PREFIX, "svc", vec![], None).unwrap();
+        record.expires_at = Some(now() - 1);
+        store.put(record).await.unwrap();
+        assert!(matches!(
+            verify_api_key(&store, &key).await,
+            Err(SecureError::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rotation_keeps_the_old_key_for_the_grace_period() {
+        let store = MemoryApiKeyStore::new();
+        let ttl = Some(Duration::from_secs(3600));
+        let (old_key, old) =
+            generate_api_key(LIVE_PREFIX, "svc", vec!["read".into()], ttl).unwrap();
+        store.put(old.clone()).await.unwrap();
+
+        let (new_key, new) = rotate_api_key(&store, LIVE_PREFIX, &old, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_ne!(new_key, old_key);
+        assert_eq!(new.id, "svc");
+        assert_eq!(new.scopes, old.scopes);
+        assert_eq!(new.expires_at, Some(new.created_at + 3600));
+        verify_api_key(&store, &new_key).await.unwrap();
+
+        // The old key now expires at the end of the grace period.
+        let rotated = verify_api_key(&store, &old_key).await.unwrap();
+        let grace_until = rotated.expires_at.unwrap();
+        assert!(grace_until <= now() + 60 && grace_until > now());
+
+        // Without grace the old key stops working at once.
+        let (_, newest) = rotate_api_key(&store, LIVE_PREFIX, &new, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(matches!(
+            verify_api_key(&store, &new_key).await,
+            Err(SecureError::Expired)
+        ));
+        assert_eq!(newest.id, "svc");
+    }
+}
@@ -0,0 +1,287 @@
+use parking_lot::RwLock;
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use rustc_hash::FxHashMap;
+use std::{fmt, sync::Arc};
+
+use super::{Secret, SecureError, random::fill_random};
+
+/// Leading byte of every ciphertext, bumped if the layout ever changes.
+const FORMAT_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+
+/// An AES-256-GCM key identified by a small integer stored in front of every
+/// ciphertext it produces.
+pub struct Key {
+    id: u32,
+    key: LessSafeKey,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Key {
+    pub fn new(id: u32, secret: &[u8]) -> Result<Self, SecureError> {
+        if secret.len() != KEY_LEN {
+            return Err(SecureError::InvalidKey);
+        }
+        let key = UnboundKey::new(&AES_256_GCM, secret).map_err(|_| SecureError::InvalidKey)?;
+        Ok(Key {
+            id,
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// Generate a random key, returning it with the secret bytes for storage.
+    pub fn generate(id: u32) -> Result<(Self, Secret<Vec<u8>>), SecureError> {
+        let mut secret = Secret::new(vec![0u8; KEY_LEN]);
+        fill_random(secret.expose_mut())?;
+        Ok((Key::new(id, secret.expose())?, secret))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+#[derive(Default)]
+struct KeyringState {
+    keys: FxHashMap<u32, Arc<Key>>,
+    primary: Option<u32>,
+}
+
+/// Encrypts with the primary key and decrypts with whichever key a
+/// ciphertext names, so keys can be rotated without re-encrypting old data
+/// up front.
+///
+/// Ciphertexts are laid out as `version (1) | key id (4, big endian) |
+/// nonce (12) | sealed data and tag`. The header is authenticated together
+/// with the caller's associated data.
+#[derive(Default)]
+pub struct Keyring {
+    state: RwLock<KeyringState>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Keyring::default()
+    }
+
+    /// Add or replace a key. The first key inserted becomes the primary one.
+    pub fn insert(&self, key: Key) {
+        let mut state = self.state.write();
+        if state.primary.is_none() {
+            state.primary = Some(key.id);
+        }
+        state.keys.insert(key.id, Arc::new(key));
+    }
+
+    /// Insert a new key and encrypt with it from now on. Older keys are kept
+    /// for decryption.
+    pub fn rotate(&self, key: Key) {
+        let mut state = self.state.write();
+        state.primary = Some(key.id);
+        state.keys.insert(key.id, Arc::new(key));
+    }
+
+    pub fn remove(&self, id: u32) -> Option<Arc<Key>> {
+        let mut state = self.state.write();
+        if state.primary == Some(id) {
+            state.primary = None;
+        }
+        state.keys.remove(&id)
+    }
+
+    pub fn primary_id(&self) -> Option<u32> {
+        self.state.read().primary
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, SecureError> {
+        let key = {
+            let state = self.state.read();
+            state
+                .primary
+                .and_then(|id| state.keys.get(&id).cloned())
+                .ok_or(SecureError::UnknownKey)?
+        };
+
+        let mut nonce = [0u8; NONCE_LEN];
+        fill_random(&mut nonce)?;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + plaintext.len() + AES_256_GCM.tag_len());
+        out.push(FORMAT_VERSION);
+        out.extend_from_slice(&key.id.to_be_bytes());
+        out.extend_from_slice(&nonce);
+
+        let mut in_out = plaintext.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(associated_data(&out, aad)),
+                &mut in_out,
+            )
+            .map_err(|_| SecureError::InvalidKey)?;
+        out.extend_from_slice(&in_out);
+
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, SecureError> {
+        if ciphertext.len() < HEADER_LEN + AES_256_GCM.tag_len() || ciphertext[0] != FORMAT_VERSION
+        {
+            return Err(SecureError::InvalidCiphertext);
+        }
+        let (header, sealed) = ciphertext.split_at(HEADER_LEN);
+
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&header[1..5]);
+        let key = self
+            .state
+            .read()
+            .keys
+            .get(&u32::from_be_bytes(id))
+            .cloned()
+            .ok_or(SecureError::UnknownKey)?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&header[5..]);
+
+        let mut in_out = sealed.to_vec();
+        let plaintext = key
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(associated_data(header, aad)),
+                &mut in_out,
+            )
+            .map_err(|_| SecureError::InvalidCiphertext)?;
+
+        Ok(plaintext.to_vec())
+    }
+}
+
+fn associated_data(header: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(header.len() + aad.len());
+    data.extend_from_slice(header);
+    data.extend_from_slice(aad);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring() -> Keyring {
+        let keyring = Keyring::new();
+        keyring.insert(Key::generate(1).unwrap().0);
+        keyring
+    }
+
+    #[test]
+    fn round_trips_with_associated_data() {
+        let keyring = keyring();
+        for plaintext in [&b""[..], b"secret", &[0xa5; 4096]] {
+            let ciphertext = keyring.encrypt(plaintext, b"user-1").unwrap();
+            assert_eq!(ciphertext[0], FORMAT_VERSION);
+            assert_eq!(&ciphertext[1..5], &1u32.to_be_bytes());
+            assert_eq!(
+                ciphertext.len(),
+                HEADER_LEN + plaintext.len() + AES_256_GCM.tag_len()
+            );
+            assert_eq!(keyring.decrypt(&ciphertext, b"user-1").unwrap(), plaintext);
+        }
+        // Every ciphertext gets its own nonce.
+        assert_ne!(
+            keyring.encrypt(b"secret", b"").unwrap(),
+            keyring.encrypt(b"secret", b"").unwrap()
+        );
+    }
+
+    #[test]
+    fn associated_data_must_match() {
+        let keyring = keyring();
+        let ciphertext = keyring.encrypt(b"secret", b"user-1").unwrap();
+        for aad in [&b"user-2"[..], b"", b"user-1 "] {
+            assert_eq!(
+                keyring.decrypt(&ciphertext, aad),
+                Err(SecureError::InvalidCiphertext)
+            );
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let keyring = keyring();
+        let ciphertext = keyring.encrypt(b"secret", b"").unwrap();
+        // Flip a bit in the nonce, the sealed data and the tag.
+        for index in [HEADER_LEN - 1, HEADER_LEN, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                keyring.decrypt(&tampered, b""),
+                Err(SecureError::InvalidCiphertext)
+            );
+        }
+
+        let mut versioned = ciphertext.clone();
+        versioned[0] = FORMAT_VERSION + 1;
+        assert_eq!(
+            keyring.decrypt(&versioned, b""),
+            Err(SecureError::InvalidCiphertext)
+        );
+        assert_eq!(
+            keyring.decrypt(&ciphertext[..HEADER_LEN + 4], b""),
+            Err(SecureError::InvalidCiphertext)
+        );
+    }
+
+    #[test]
+    fn key_id_is_authenticated() {
+        let keyring = keyring();
+        let (second, secret) = Key::generate(2).unwrap();
+        keyring.insert(second);
+        let ciphertext = keyring.encrypt(b"secret", b"").unwrap();
+
+        // Pointing the header at another key fails even with the same bytes.
+        keyring.insert(Key::new(3, secret.expose()).unwrap());
+        let mut retargeted = ciphertext.clone();
+        retargeted[1..5].copy_from_slice(&3u32.to_be_bytes());
+        assert!(keyring.decrypt(&retargeted, b"").is_err());
+
+        retargeted[1..5].copy_from_slice(&9u32.to_be_bytes());
+        assert_eq!(
+            keyring.decrypt(&retargeted, b""),
+            Err(SecureError::UnknownKey)
+        );
+    }
+
+    #[test]
+    fn old_ciphertexts_decrypt_after_rotation() {
+        let keyring = keyring();
+        let old = keyring.encrypt(b"before", b"").unwrap();
+
+        keyring.rotate(Key::generate(2).unwrap().0);
+        assert_eq!(keyring.primary_id(), Some(2));
+        let new = keyring.encrypt(b"after", b"").unwrap();
+        assert_eq!(&new[1..5], &2u32.to_be_bytes());
+        assert_eq!(keyring.decrypt(&old, b"").unwrap(), b"before");
+        assert_eq!(keyring.decrypt(&new, b"").unwrap(), b"after");
+
+        keyring.remove(1);
+        assert_eq!(keyring.decrypt(&old, b""), Err(SecureError::UnknownKey));
+        keyring.remove(2);
+        assert_eq!(keyring.encrypt(b"x", b""), Err(SecureError::UnknownKey));
+    }
+
+    #[test]
+    fn keys_must_be_256_bits() {
+        assert!(Key::new(1, &[0; 16]).is_err());
+        assert!(Key::new(1, &[0; 33]).is_err());
+        assert!(Key::new(1, &[0; 32]).is_ok());
+    }
+}
@@ -0,0 +1,41 @@
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SecureError {
+    #[error("invalid secret")]
+    InvalidSecret,
+    #[error("invalid code")]
+    InvalidCode,
+    #[error("code expired")]
+    Expired,
+    #[error("code already used")]
+    Replayed,
+    #[error("too many failed attempts")]
+    Locked,
+    #[error("invalid provisioning uri")]
+    InvalidUri,
+    #[error("failed to render qr code")]
+    QrRender,
+    #[error("qr code scale out of range")]
+    InvalidScale,
+    #[error("random number generator failure")]
+    Random,
+    #[error("invalid password")]
+    InvalidPassword,
+    #[error("malformed password hash")]
+    InvalidHash,
+    #[error("invalid key")]
+    InvalidKey,
+    #[error("unknown key")]
+    UnknownKey,
+    #[error("malformed token")]
+    InvalidToken,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token not yet valid")]
+    NotYetValid,
+    #[error("invalid {0} claim")]
+    InvalidClaim(&'static str),
+    #[error("invalid ciphertext")]
+    InvalidCiphertext,
+    #[error("store error: {0}")]
+    Store(String),
+}
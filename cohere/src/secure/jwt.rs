@@ -0,0 +1,809 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use parking_lot::RwLock;
+use ring::{
+    hmac,
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::{fmt, sync::Arc, time::Duration};
+
+use super::{Secret, SecureError};
+use crate::time::now;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    HS256,
+    ES256,
+    EdDSA,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub alg: Algorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    pub fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::Single(aud) => aud == audience,
+            Audience::Multiple(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+/// Registered claims plus application specific ones flattened from `T`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Claims<T = Map<String, Value>> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(flatten)]
+    pub custom: T,
+}
+
+impl<T> Claims<T> {
+    /// Claims issued now and expiring after `ttl`.
+    pub fn new(custom: T, ttl: Duration) -> Self {
+        let now = now();
+        Claims {
+            iss: None,
+            sub: None,
+            aud: None,
+            exp: Some(now.saturating_add(ttl.as_secs())),
+            nbf: None,
+            iat: Some(now),
+            jti: None,
+            custom,
+        }
+    }
+
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.iss = Some(issuer.into());
+        self
+    }
+
+    pub fn with_subject(mut self, subject: impl Into<String>) -> Self {
+        self.sub = Some(subject.into());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.aud = Some(Audience::Single(audience.into()));
+        self
+    }
+}
+
+/// Checks applied to the registered claims once the signature is verified.
+#[derive(Clone, Debug)]
+pub struct Validation {
+    /// Allowed clock skew in seconds for `exp`, `nbf` and `iat`.
+    pub leeway: u64,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub require_exp: bool,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Validation {
+            leeway: 60,
+            issuer: None,
+            audience: None,
+            require_exp: true,
+        }
+    }
+}
+
+/// A public key in JSON Web Key format (RFC 7517).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+enum KeyMaterial {
+    Hmac(hmac::Key),
+    EcdsaPair(EcdsaKeyPair),
+    Ed25519Pair(Ed25519KeyPair),
+    EcdsaPublic(Vec<u8>),
+    Ed25519Public(Vec<u8>),
+}
+
+/// A signing or verification key identified by `kid`.
+pub struct Key {
+    kid: String,
+    material: KeyMaterial,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("kid", &self.kid)
+            .field("alg", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Key {
+    pub fn hs256(kid: impl Into<String>, secret: &[u8]) -> Result<Self, SecureError> {
+        if secret.len() < 32 {
+            return Err(SecureError::InvalidKey);
+        }
+        Ok(Key {
+            kid: kid.into(),
+            material: KeyMaterial::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret)),
+        })
+    }
+
+    pub fn es256_pkcs8(kid: impl Into<String>, pkcs8: &[u8]) -> Result<Self, SecureError> {
+        let pair = EcdsaKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| SecureError::InvalidKey)?;
+        Ok(Key {
+            kid: kid.into(),
+            material: KeyMaterial::EcdsaPair(pair),
+        })
+    }
+
+    pub fn ed25519_pkcs8(kid: impl Into<String>, pkcs8: &[u8]) -> Result<Self, SecureError> {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|_| SecureError::InvalidKey)?;
+        Ok(Key {
+            kid: kid.into(),
+            material: KeyMaterial::Ed25519Pair(pair),
+        })
+    }
+
+    /// Verification-only P-256 key from an uncompressed SEC1 point.
+    pub fn es256_public(kid: impl Into<String>, public: &[u8]) -> Result<Self, SecureError> {
+        if public.len() != 65 || public[0] != 0x04 {
+            return Err(SecureError::InvalidKey);
+        }
+        Ok(Key {
+            kid: kid.into(),
+            material: KeyMaterial::EcdsaPublic(public.to_vec()),
+        })
+    }
+
+    /// Verification-only Ed25519 key.
+    pub fn ed25519_public(kid: impl Into<String>, public: &[u8]) -> Result<Self, SecureError> {
+        if public.len() != 32 {
+            return Err(SecureError::InvalidKey);
+        }
+        Ok(Key {
+            kid: kid.into(),
+            material: KeyMaterial::Ed25519Public(public.to_vec()),
+        })
+    }
+
+    /// Generate a new P-256 key pair, returning the key and its PKCS#8 encoding
+    /// for storage.
+    pub fn generate_es256(kid: impl Into<String>) -> Result<(Self, Secret<Vec<u8>>), SecureError> {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| SecureError::Random)?;
+        let key = Key::es256_pkcs8(kid, pkcs8.as_ref())?;
+        Ok((key, Secret::new(pkcs8.as_ref().to_vec())))
+    }
+
+    /// Generate a new Ed25519 key pair, returning the key and its PKCS#8
+    /// encoding for storage.
+    pub fn generate_ed25519(
+        kid: impl Into<String>,
+    ) -> Result<(Self, Secret<Vec<u8>>), SecureError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| SecureError::Random)?;
+        let key = Key::ed25519_pkcs8(kid, pkcs8.as_ref())?;
+        Ok((key, Secret::new(pkcs8.as_ref().to_vec())))
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self.material {
+            KeyMaterial::Hmac(_) => Algorithm::HS256,
+            KeyMaterial::EcdsaPair(_) | KeyMaterial::EcdsaPublic(_) => Algorithm::ES256,
+            KeyMaterial::Ed25519Pair(_) | KeyMaterial::Ed25519Public(_) => Algorithm::EdDSA,
+        }
+    }
+
+    /// Public key bytes of an asymmetric key; `None` for HMAC keys.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        match &self.material {
+            KeyMaterial::Hmac(_) => None,
+            KeyMaterial::EcdsaPair(pair) => Some(pair.public_key().as_ref()),
+            KeyMaterial::Ed25519Pair(pair) => Some(pair.public_key().as_ref()),
+            KeyMaterial::EcdsaPublic(public) | KeyMaterial::Ed25519Public(public) => Some(public),
+        }
+    }
+
+    /// Public part of an asymmetric key as a JWK; `None` for HMAC keys.
+    pub fn to_jwk(&self) -> Option<Jwk> {
+        let public = self.public_key()?;
+        let (kty, crv, x, y) = match self.algorithm() {
+            Algorithm::HS256 => return None,
+            Algorithm::ES256 => (
+                "EC",
+                "P-256",
+                &public[1..33],
+                Some(URL_SAFE_NO_PAD.encode(&public[33..65])),
+            ),
+            Algorithm::EdDSA => ("OKP", "Ed25519", public, None),
+        };
+        Some(Jwk {
+            kty: kty.into(),
+            crv: Some(crv.into()),
+            x: Some(URL_SAFE_NO_PAD.encode(x)),
+            y,
+            kid: Some(self.kid.clone()),
+            alg: Some(format!("{:?}", self.algorithm())),
+            key_use: Some("sig".into()),
+        })
+    }
+
+    /// Verification key from a JWK. Only P-256 and Ed25519 signing keys are
+    /// supported.
+    pub fn from_jwk(jwk: &Jwk) -> Result<Self, SecureError> {
+        let kid = jwk.kid.clone().ok_or(SecureError::InvalidKey)?;
+        if jwk
+            .key_use
+            .as_deref()
+            .is_some_and(|key_use| key_use != "sig")
+        {
+            return Err(SecureError::InvalidKey);
+        }
+        let decode = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+                .ok_or(SecureError::InvalidKey)
+        };
+
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("EC", Some("P-256")) => {
+                let mut public = vec![0x04];
+                public.extend(decode(&jwk.x)?);
+                public.extend(decode(&jwk.y)?);
+                Key::es256_public(kid, &public)
+            }
+            ("OKP", Some("Ed25519")) => Key::ed25519_public(kid, &decode(&jwk.x)?),
+            _ => Err(SecureError::InvalidKey),
+        }
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, SecureError> {
+        match &self.material {
+            KeyMaterial::Hmac(key) => Ok(hmac::sign(key, msg).as_ref().to_vec()),
+            KeyMaterial::EcdsaPair(pair) => pair
+                .sign(&SystemRandom::new(), msg)
+                .map(|sig| sig.as_ref().to_vec())
+                .map_err(|_| SecureError::Random),
+            KeyMaterial::Ed25519Pair(pair) => Ok(pair.sign(msg).as_ref().to_vec()),
+            KeyMaterial::EcdsaPublic(_) | KeyMaterial::Ed25519Public(_) => {
+                Err(SecureError::InvalidKey)
+            }
+        }
+    }
+
+    fn verify(&self, msg: &[u8], sig: &[u8]) -> Result<(), SecureError> {
+        let result = match &self.material {
+            KeyMaterial::Hmac(key) => hmac::verify(key, msg, sig),
+            KeyMaterial::EcdsaPair(_) | KeyMaterial::EcdsaPublic(_) => UnparsedPublicKey::new(
+                &signature::ECDSA_P256_SHA256_FIXED,
+                self.public_key().unwrap_or_default(),
+            )
+            .verify(msg, sig),
+            KeyMaterial::Ed25519Pair(_) | KeyMaterial::Ed25519Public(_) => {
+                UnparsedPublicKey::new(&signature::ED25519, self.public_key().unwrap_or_default())
+                    .verify(msg, sig)
+            }
+        };
+        result.map_err(|_| SecureError::InvalidSignature)
+    }
+}
+
+/// Sign the claims with the given key, setting `kid` in the header.
+pub fn encode<T: Serialize>(key: &Key, claims: &Claims<T>) -> Result<String, SecureError> {
+    let header = Header {
+        alg: key.algorithm(),
+        kid: Some(key.kid.clone()),
+        typ: Some("JWT".into()),
+    };
+    let header = serde_json::to_vec(&header).map_err(|_| SecureError::InvalidToken)?;
+    let claims = serde_json::to_vec(claims).map_err(|_| SecureError::InvalidToken)?;
+
+    let mut token = URL_SAFE_NO_PAD.encode(header);
+    token.push('.');
+    token.push_str(&URL_SAFE_NO_PAD.encode(claims));
+
+    let sig = key.sign(token.as_bytes())?;
+    token.push('.');
+    token.push_str(&URL_SAFE_NO_PAD.encode(sig));
+    Ok(token)
+}
+
+/// Decode the header without verifying anything, to find out which key a
+/// token claims to be signed with.
+pub fn decode_header(token: &str) -> Result<Header, SecureError> {
+    let (header, _) = token.split_once('.').ok_or(SecureError::InvalidToken)?;
+    let header = URL_SAFE_NO_PAD
+        .decode(header)
+        .map_err(|_| SecureError::InvalidToken)?;
+    serde_json::from_slice(&header).map_err(|_| SecureError::InvalidToken)
+}
+
+/// Verify the signature with `key` and validate the registered claims.
+pub fn decode<T: DeserializeOwned>(
+    token: &str,
+    key: &Key,
+    validation: &Validation,
+) -> Result<Claims<T>, SecureError> {
+    let header = decode_header(token)?;
+    if header.alg != key.algorithm() {
+        return Err(SecureError::InvalidSignature);
+    }
+
+    let (msg, sig) = token.rsplit_once('.').ok_or(SecureError::InvalidToken)?;
+    let (_, payload) = msg.split_once('.').ok_or(SecureError::InvalidToken)?;
+    let sig = URL_SAFE_NO_PAD
+        .decode(sig)
+        .map_err(|_| SecureError::InvalidToken)?;
+    key.verify(msg.as_bytes(), &sig)?;
+
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| SecureError::InvalidToken)?;
+    let claims: Claims<T> =
+        serde_json::from_slice(&payload).map_err(|_| SecureError::InvalidToken)?;
+    validate_claims(&claims, validation)?;
+
+    Ok(claims)
+}
+
+fn validate_claims<T>(claims: &Claims<T>, validation: &Validation) -> Result<(), SecureError> {
+    let now = now();
+
+    match claims.exp {
+        Some(exp) if now > exp.saturating_add(validation.leeway) => {
+            return Err(SecureError::Expired);
+        }
+        None if validation.require_exp => return Err(SecureError::InvalidClaim("exp")),
+        _ => {}
+    }
+    if let Some(nbf) = claims.nbf
+        && nbf > now.saturating_add(validation.leeway)
+    {
+        return Err(SecureError::NotYetValid);
+    }
+    if let Some(iat) = claims.iat
+        && iat > now.saturating_add(validation.leeway)
+    {
+        return Err(SecureError::NotYetValid);
+    }
+    if let Some(issuer) = &validation.issuer
+        && claims.iss.as_ref() != Some(issuer)
+    {
+        return Err(SecureError::InvalidClaim("iss"));
+    }
+    if let Some(audience) = &validation.audience
+        && !claims
+            .aud
+            .as_ref()
+            .is_some_and(|aud| aud.contains(audience))
+    {
+        return Err(SecureError::InvalidClaim("aud"));
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct KeyringState {
+    keys: FxHashMap<String, Arc<Key>>,
+    primary: Option<String>,
+}
+
+/// Set of keys selected by `kid`. Tokens are signed with the primary key and
+/// verified with whichever key their header names, so keys can be rotated by
+/// inserting a new primary while the previous one is still accepted.
+#[derive(Default)]
+pub struct Keyring {
+    state: RwLock<KeyringState>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Keyring::default()
+    }
+
+    /// Add or replace a key. The first key inserted becomes the primary one.
+    pub fn insert(&self, key: Key) {
+        let mut state = self.state.write();
+        if state.primary.is_none() {
+            state.primary = Some(key.kid.clone());
+        }
+        state.keys.insert(key.kid.clone(), Arc::new(key));
+    }
+
+    /// Insert a new key and make it the primary one. Previous keys stay in the
+    /// keyring, so tokens they signed keep verifying until they are removed.
+    pub fn rotate(&self, key: Key) {
+        let mut state = self.state.write();
+        state.primary = Some(key.kid.clone());
+        state.keys.insert(key.kid.clone(), Arc::new(key));
+    }
+
+    pub fn set_primary(&self, kid: &str) -> Result<(), SecureError> {
+        let mut state = self.state.write();
+        if !state.keys.contains_key(kid) {
+            return Err(SecureError::UnknownKey);
+        }
+        state.primary = Some(kid.to_owned());
+        Ok(())
+    }
+
+    /// Remove a key. Removing the primary key leaves the keyring unable to
+    /// sign until another one is made primary.
+    pub fn remove(&self, kid: &str) -> Option<Arc<Key>> {
+        let mut state = self.state.write();
+        if state.primary.as_deref() == Some(kid) {
+            state.primary = None;
+        }
+        state.keys.remove(kid)
+    }
+
+    pub fn get(&self, kid: &str) -> Option<Arc<Key>> {
+        self.state.read().keys.get(kid).cloned()
+    }
+
+    pub fn primary(&self) -> Option<Arc<Key>> {
+        let state = self.state.read();
+        state
+            .primary
+            .as_ref()
+            .and_then(|kid| state.keys.get(kid).cloned())
+    }
+
+    pub fn keys(&self) -> Vec<Arc<Key>> {
+        self.state.read().keys.values().cloned().collect()
+    }
+
+    /// Public keys of the keyring as a JWK Set. HMAC keys are never included.
+    pub fn jwks(&self) -> JwkSet {
+        let mut keys: Vec<Jwk> = self.keys().iter().filter_map(|key| key.to_jwk()).collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        JwkSet { keys }
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &Claims<T>) -> Result<String, SecureError> {
+        let key = self.primary().ok_or(SecureError::UnknownKey)?;
+        encode(&key, claims)
+    }
+
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<Claims<T>, SecureError> {
+        let header = decode_header(token)?;
+        let key = match header.kid {
+            Some(kid) => self.get(&kid),
+            None => {
+                let keys = self.keys();
+                if keys.len() == 1 {
+                    keys.into_iter().next()
+                } else {
+                    None
+                }
+            }
+        }
+        .ok_or(SecureError::UnknownKey)?;
+        decode(token, &key, validation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Custom = Map<String, Value>;
+
+    fn keys() -> Vec<Key> {
+        vec![
+            Key::hs256("hs", &[7; 32]).unwrap(),
+            Key::generate_es256("es").unwrap().0,
+            Key::generate_ed25519("ed").unwrap().0,
+        ]
+    }
+
+    fn claims() -> Claims<Custom> {
+        let mut custom = Map::new();
+        custom.insert("role".into(), "admin".into());
+        Claims::new(custom, Duration::from_secs(300))
+            .with_issuer("issuer")
+            .with_subject("user")
+            .with_audience("api")
+    }
+
+    /// A token with any header, signed by `key` over whatever it names.
+    fn forge(header: Value, claims: &Claims<Custom>, key: &Key) -> String {
+        let mut token = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+        token.push('.');
+        token.push_str(&URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap()));
+        let sig = key.sign(token.as_bytes()).unwrap();
+        token.push('.');
+        token.push_str(&URL_SAFE_NO_PAD.encode(sig));
+        token
+    }
+
+    #[test]
+    fn round_trips_with_every_algorithm() {
+        for key in keys() {
+            let token = encode(&key, &claims()).unwrap();
+            let header = decode_header(&token).unwrap();
+            assert_eq!(header.alg, key.algorithm());
+            assert_eq!(header.kid.as_deref(), Some(key.kid()));
+
+            let decoded: Claims<Custom> = decode(&token, &key, &Validation::default()).unwrap();
+            assert_eq!(decoded.iss.as_deref(), Some("issuer"));
+            assert_eq!(decoded.sub.as_deref(), Some("user"));
+            assert_eq!(decoded.aud, Some(Audience::Single("api".into())));
+            assert_eq!(decoded.custom["role"], "admin");
+        }
+    }
+
+    #[test]
+    fn asymmetric_tokens_verify_with_the_public_key() {
+        for key in keys().into_iter().skip(1) {
+            let token = encode(&key, &claims()).unwrap();
+            let public = Key::from_jwk(&key.to_jwk().unwrap()).unwrap();
+            decode::<Custom>(&token, &public, &Validation::default()).unwrap();
+            assert!(matches!(public.sign(b"msg"), Err(SecureError::InvalidKey)));
+        }
+    }
+
+    #[test]
+    fn mismatched_algorithm_is_rejected() {
+        let [hs, es, ed] = <[Key; 3]>::try_from(keys()).unwrap();
+
+        // The header names a different algorithm from the key's.
+        let token = forge(json_header("ES256", "hs"), &claims(), &hs);
+        assert!(matches!(
+            decode::<Custom>(&token, &hs, &Validation::default()),
+            Err(SecureError::InvalidSignature)
+        ));
+
+        // A token signed by one key never verifies with another.
+        let token = encode(&es, &claims()).unwrap();
+        assert!(matches!(
+            decode::<Custom>(&token, &ed, &Validation::default()),
+            Err(SecureError::InvalidSignature)
+        ));
+        let other = Key::generate_es256("es").unwrap().0;
+        assert!(matches!(
+            decode::<Custom>(&token, &other, &Validation::default()),
+            Err(SecureError::InvalidSignature)
+        ));
+    }
+
+    fn json_header(alg: &str, kid: &str) -> Value {
+        serde_json::json!({ "alg": alg, "kid": kid, "typ": "JWT" })
+    }
+
+    #[test]
+    fn alg_none_is_rejected() {
+        let hs = Key::hs256("hs", &[7; 32]).unwrap();
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims()).unwrap());
+        for token in [
+            format!("{header}.{payload}."),
+            format!("{header}.{payload}"),
+        ] {
+            assert!(matches!(
+                decode::<Custom>(&token, &hs, &Validation::default()),
+                Err(SecureError::InvalidToken)
+            ));
+        }
+
+        let keyring = Keyring::new();
+        keyring.insert(hs);
+        assert!(
+            keyring
+                .verify::<Custom>(&format!("{header}.{payload}."), &Validation::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn unknown_kid_is_rejected() {
+        let keyring = Keyring::new();
+        keyring.insert(Key::hs256("current", &[7; 32]).unwrap());
+
+        let stranger = Key::hs256("stranger", &[7; 32]).unwrap();
+        let token = encode(&stranger, &claims()).unwrap();
+        assert!(matches!(
+            keyring.verify::<Custom>(&token, &Validation::default()),
+            Err(SecureError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn time_claims_allow_only_the_leeway() {
+        let key = Key::hs256("hs", &[7; 32]).unwrap();
+        let validation = Validation {
+            leeway: 30,
+            ..Validation::default()
+        };
+        let check = |claims: Claims<Custom>| {
+            decode::<Custom>(&encode(&key, &claims).unwrap(), &key, &validation)
+        };
+        let now = now();
+
+        let expired_within_leeway = Claims {
+            exp: Some(now - 20),
+            ..claims()
+        };
+        assert!(check(expired_within_leeway).is_ok());
+        let expired = Claims {
+            exp: Some(now - 40),
+            ..claims()
+        };
+        assert!(matches!(check(expired), Err(SecureError::Expired)));
+
+        let early_within_leeway = Claims {
+            nbf: Some(now + 20),
+            ..claims()
+        };
+        assert!(check(early_within_leeway).is_ok());
+        let early = Claims {
+            nbf: Some(now + 40),
+            ..claims()
+        };
+        assert!(matches!(check(early), Err(SecureError::NotYetValid)));
+        let issued_in_future = Claims {
+            iat: Some(now + 40),
+            ..claims()
+        };
+        assert!(matches!(
+            check(issued_in_future),
+            Err(SecureError::NotYetValid)
+        ));
+
+        let no_exp = Claims {
+            exp: None,
+            ..claims()
+        };
+        assert!(matches!(
+            check(no_exp.clone()),
+            Err(SecureError::InvalidClaim("exp"))
+        ));
+        let optional = Validation {
+            require_exp: false,
+            ..validation.clone()
+        };
+        decode::<Custom>(&encode(&key, &no_exp).unwrap(), &key, &optional).unwrap();
+    }
+
+    #[test]
+    fn issuer_and_audience_must_match() {
+        let key = Key::hs256("hs", &[7; 32]).unwrap();
+        let token = encode(&key, &claims()).unwrap();
+        let expecting = |issuer: &str, audience: &str| Validation {
+            issuer: Some(issuer.into()),
+            audience: Some(audience.into()),
+            ..Validation::default()
+        };
+
+        decode::<Custom>(&token, &key, &expecting("issuer", "api")).unwrap();
+        assert!(matches!(
+            decode::<Custom>(&token, &key, &expecting("other", "api")),
+            Err(SecureError::InvalidClaim("iss"))
+        ));
+        assert!(matches!(
+            decode::<Custom>(&token, &key, &expecting("issuer", "other")),
+            Err(SecureError::InvalidClaim("aud"))
+        ));
+
+        let many = Claims {
+            aud: Some(Audience::Multiple(vec!["web".into(), "api".into()])),
+            ..claims()
+        };
+        let token = encode(&key, &many).unwrap();
+        decode::<Custom>(&token, &key, &expecting("issuer", "api")).unwrap();
+    }
+
+    #[test]
+    fn rotated_out_keys_stop_verifying() {
+        let keyring = Keyring::new();
+        keyring.insert(Key::generate_ed25519("old").unwrap().0);
+        let old_token = keyring.sign(&claims()).unwrap();
+
+        keyring.rotate(Key::generate_ed25519("new").unwrap().0);
+        let new_token = keyring.sign(&claims()).unwrap();
+        assert_eq!(
+            decode_header(&new_token).unwrap().kid.as_deref(),
+            Some("new")
+        );
+        // Until the old key is removed, its tokens still verify.
+        keyring
+            .verify::<Custom>(&old_token, &Validation::default())
+            .unwrap();
+        assert_eq!(keyring.jwks().keys.len(), 2);
+
+        keyring.remove("old");
+        assert!(matches!(
+            keyring.verify::<Custom>(&old_token, &Validation::default()),
+            Err(SecureError::UnknownKey)
+        ));
+        keyring
+            .verify::<Custom>(&new_token, &Validation::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        for key in keys() {
+            let token = encode(&key, &claims()).unwrap();
+            let (header, rest) = token.split_once('.').unwrap();
+            let (_, sig) = rest.split_once('.').unwrap();
+            let forged = Claims {
+                sub: Some("someone else".into()),
+                ..claims()
+            };
+            let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+            let tampered = format!("{header}.{payload}.{sig}");
+            assert!(matches!(
+                decode::<Custom>(&tampered, &key, &Validation::default()),
+                Err(SecureError::InvalidSignature)
+            ));
+        }
+    }
+}
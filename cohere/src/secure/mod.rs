@@ -1,3 +1,25 @@
-mod totp;
+pub mod apikey;
+pub mod crypto;
+mod error;
+pub mod jwt;
+pub mod password;
+mod qr;
+mod random;
+mod recovery;
+mod secret;
+pub mod signature;
+pub mod token;
+pub mod totp;
 
+pub use error::SecureError;
+pub use random::generate_secret;
+pub use recovery::{RecoveryCodes, generate_recovery_codes};
+pub use secret::{HeapBytes, Secret};
 pub use totp::validate_totp;
+
+/// Compare two byte strings in time that depends only on their lengths.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    use subtle::ConstantTimeEq;
+
+    a.ct_eq(b).into()
+}
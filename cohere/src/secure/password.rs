@@ -0,0 +1,287 @@
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use ring::pbkdf2;
+use std::num::NonZeroU32;
+
+use super::{SecureError, random::fill_random};
+
+const ALGORITHM_ID: &str = "pbkdf2-sha256";
+
+/// Upper bound on iterations accepted from a stored hash, so a tampered
+/// record cannot make verification arbitrarily slow.
+const MAX_ITERATIONS: u32 = 10_000_000;
+
+/// Upper bound on the hash length, for the same reason. Longer PBKDF2-SHA256
+/// output adds no strength, only work.
+const MAX_HASH_LEN: usize = 64;
+
+/// Parameters new hashes are created with. Stored hashes weaker than the
+/// policy are reported through [`Verified::needs_rehash`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub iterations: u32,
+    pub salt_len: usize,
+    pub hash_len: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            iterations: 600_000,
+            salt_len: 16,
+            hash_len: 32,
+        }
+    }
+}
+
+/// Outcome of a successful verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Verified {
+    /// The stored hash uses weaker parameters than the current policy and
+    /// should be replaced with a fresh hash of the same password.
+    pub needs_rehash: bool,
+}
+
+impl PasswordPolicy {
+    /// Hash the password into a PHC string such as
+    /// `$pbkdf2-sha256$i=600000,l=32$<salt>$<hash>`.
+    pub fn hash(&self, password: &str) -> Result<String, SecureError> {
+        let iterations = NonZeroU32::new(self.iterations).ok_or(SecureError::InvalidHash)?;
+        if self.salt_len == 0 || self.hash_len == 0 || self.hash_len > MAX_HASH_LEN {
+            return Err(SecureError::InvalidHash);
+        }
+
+        let mut salt = vec![0u8; self.salt_len];
+        fill_random(&mut salt)?;
+
+        let mut hash = vec![0u8; self.hash_len];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+
+        Ok(format!(
+            "${}$i={},l={}${}${}",
+            ALGORITHM_ID,
+            self.iterations,
+            self.hash_len,
+            STANDARD_NO_PAD.encode(&salt),
+            STANDARD_NO_PAD.encode(&hash),
+        ))
+    }
+
+    /// Verify the password against a PHC string in constant time.
+    pub fn verify(&self, password: &str, phc: &str) -> Result<Verified, SecureError> {
+        let stored = parse_phc(phc)?;
+
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            stored.iterations,
+            &stored.salt,
+            password.as_bytes(),
+            &stored.hash,
+        )
+        .map_err(|_| SecureError::InvalidPassword)?;
+
+        Ok(Verified {
+            needs_rehash: stored.iterations.get() < self.iterations
+                || stored.salt.len() < self.salt_len
+                || stored.hash.len() < self.hash_len,
+        })
+    }
+}
+
+/// Hash the password with the default policy.
+pub fn hash_password(password: &str) -> Result<String, SecureError> {
+    PasswordPolicy::default().hash(password)
+}
+
+/// Verify the password against a PHC string using the default policy for
+/// rehash detection.
+pub fn verify_password(password: &str, phc: &str) -> Result<Verified, SecureError> {
+    PasswordPolicy::default().verify(password, phc)
+}
+
+struct StoredHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+fn parse_phc(phc: &str) -> Result<StoredHash, SecureError> {
+    let mut parts = phc.split('$');
+    if parts.next() != Some("") || parts.next() != Some(ALGORITHM_ID) {
+        return Err(SecureError::InvalidHash);
+    }
+    let (Some(params), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(SecureError::InvalidHash);
+    };
+
+    let mut iterations = None;
+    let mut hash_len = None;
+    for param in params.split(',') {
+        match param.split_once('=') {
+            Some(("i", value)) => iterations = value.parse::<u32>().ok(),
+            Some(("l", value)) => {
+                hash_len = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| SecureError::InvalidHash)?,
+                )
+            }
+            _ => return Err(SecureError::InvalidHash),
+        }
+    }
+    let iterations = iterations
+        .filter(|i| *i <= MAX_ITERATIONS)
+        .and_then(NonZeroU32::new)
+        .ok_or(SecureError::InvalidHash)?;
+
+    let salt = STANDARD_NO_PAD
+        .decode(salt)
+        .map_err(|_| SecureError::InvalidHash)?;
+    // Checked before decoding, so an oversized hash is never allocated.
+    if hash.len() > MAX_HASH_LEN.div_ceil(3) * 4 {
+        return Err(SecureError::InvalidHash);
+    }
+    let hash = STANDARD_NO_PAD
+        .decode(hash)
+        .map_err(|_| SecureError::InvalidHash)?;
+    if hash.is_empty() || hash.len() > MAX_HASH_LEN || hash_len.is_some_and(|len| len != hash.len())
+    {
+        return Err(SecureError::InvalidHash);
+    }
+
+    Ok(StoredHash {
+        iterations,
+        salt,
+        hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PBKDF2-HMAC-SHA256 of `password` with salt `salt`, 4096 iterations.
+    const KNOWN_VECTOR: &str =
+        "$pbkdf2-sha256$i=4096,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o";
+
+    fn weak_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            iterations: 1000,
+            salt_len: 16,
+            hash_len: 32,
+        }
+    }
+
+    #[test]
+    fn verifies_known_vector() {
+        let policy = PasswordPolicy {
+            iterations: 4096,
+            salt_len: 4,
+            hash_len: 32,
+        };
+        assert_eq!(
+            policy.verify("password", KNOWN_VECTOR),
+            Ok(Verified {
+                needs_rehash: false
+            })
+        );
+        assert_eq!(
+            policy.verify("passw0rd", KNOWN_VECTOR),
+            Err(SecureError::InvalidPassword)
+        );
+    }
+
+    #[test]
+    fn hash_round_trip() {
+        let policy = weak_policy();
+        let phc = policy.hash("correct horse").unwrap();
+
+        assert!(phc.starts_with("$pbkdf2-sha256$i=1000,l=32$"));
+        assert_eq!(
+            policy.verify("correct horse", &phc),
+            Ok(Verified {
+                needs_rehash: false
+            })
+        );
+        assert_eq!(
+            policy.verify("battery staple", &phc),
+            Err(SecureError::InvalidPassword)
+        );
+    }
+
+    #[test]
+    fn needs_rehash_when_policy_is_stronger() {
+        let phc = weak_policy().hash("correct horse").unwrap();
+
+        let more_iterations = PasswordPolicy {
+            iterations: 2000,
+            ..weak_policy()
+        };
+        let longer_salt = PasswordPolicy {
+            salt_len: 32,
+            ..weak_policy()
+        };
+        let longer_hash = PasswordPolicy {
+            hash_len: 64,
+            ..weak_policy()
+        };
+        for policy in [more_iterations, longer_salt, longer_hash] {
+            assert_eq!(
+                policy.verify("correct horse", &phc),
+                Ok(Verified { needs_rehash: true }),
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_phc() {
+        let long_hash = STANDARD_NO_PAD.encode([0u8; MAX_HASH_LEN + 1]);
+        let malformed = [
+            "",
+            "pbkdf2-sha256$i=4096,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$argon2id$i=4096,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,l=32$c2FsdA",
+            "$pbkdf2-sha256$i=4096,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o$",
+            "$pbkdf2-sha256$l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=0,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=99999999,l=32$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,l=16$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,l=x$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,m=1$c2FsdA$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,l=32$c2FsdA!$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o",
+            "$pbkdf2-sha256$i=4096,l=32$c2FsdA$",
+        ];
+        for phc in malformed {
+            assert_eq!(
+                verify_password("password", phc),
+                Err(SecureError::InvalidHash),
+                "{:?}",
+                phc
+            );
+        }
+
+        let oversized = format!("$pbkdf2-sha256$i=4096$c2FsdA${}", long_hash);
+        assert_eq!(
+            verify_password("password", &oversized),
+            Err(SecureError::InvalidHash)
+        );
+    }
+
+    #[test]
+    fn rejects_unbounded_policy() {
+        let policy = PasswordPolicy {
+            hash_len: MAX_HASH_LEN + 1,
+            ..weak_policy()
+        };
+        assert_eq!(policy.hash("password"), Err(SecureError::InvalidHash));
+    }
+}
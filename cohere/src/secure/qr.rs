@@ -0,0 +1,103 @@
+use png::{BitDepth, ColorType, Encoder};
+use qrcode::{
+    Color, EcLevel, QrCode,
+    render::{svg, unicode::Dense1x2},
+};
+
+use super::{SecureError, totp::Totp};
+
+/// Modules of light border required around the symbol by the QR specification.
+const QUIET_ZONE: usize = 4;
+/// Largest PNG scale accepted, which already gives images over 5000 pixels
+/// wide for the longest provisioning URIs.
+const MAX_PNG_SCALE: u32 = 32;
+
+impl Totp {
+    /// Render the provisioning URI as an SVG document.
+    pub fn to_qr_svg(&self) -> Result<String, SecureError> {
+        let code = self.qr_code()?;
+        Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .dark_color(svg::Color("#000000"))
+            .light_color(svg::Color("#ffffff"))
+            .build())
+    }
+
+    /// Render the provisioning URI as a grayscale PNG image, `scale` pixels
+    /// per module. `scale` must be between 1 and 32.
+    pub fn to_qr_png(&self, scale: u32) -> Result<Vec<u8>, SecureError> {
+        if !(1..=MAX_PNG_SCALE).contains(&scale) {
+            return Err(SecureError::InvalidScale);
+        }
+        let code = self.qr_code()?;
+        let scale = scale as usize;
+        let modules = code.width();
+        let size = modules
+            .checked_add(2 * QUIET_ZONE)
+            .and_then(|width| width.checked_mul(scale))
+            .ok_or(SecureError::InvalidScale)?;
+        let dimension = u32::try_from(size).map_err(|_| SecureError::InvalidScale)?;
+        let colors = code.to_colors();
+
+        let mut pixels = vec![0xffu8; size.checked_mul(size).ok_or(SecureError::InvalidScale)?];
+        for (i, color) in colors.iter().enumerate() {
+            if *color != Color::Dark {
+                continue;
+            }
+            let x = (i % modules + QUIET_ZONE) * scale;
+            let y = (i / modules + QUIET_ZONE) * scale;
+            for row in y..y + scale {
+                pixels[row * size + x..row * size + x + scale].fill(0x00);
+            }
+        }
+
+        let mut buffer = Vec::new();
+        let mut encoder = Encoder::new(&mut buffer, dimension, dimension);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|_| SecureError::QrRender)?;
+        writer
+            .write_image_data(&pixels)
+            .map_err(|_| SecureError::QrRender)?;
+        writer.finish().map_err(|_| SecureError::QrRender)?;
+
+        Ok(buffer)
+    }
+
+    /// Render the provisioning URI with Unicode half blocks for display in a
+    /// terminal with a dark background.
+    pub fn to_qr_unicode(&self) -> Result<String, SecureError> {
+        let code = self.qr_code()?;
+        Ok(code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+
+    fn qr_code(&self) -> Result<QrCode, SecureError> {
+        QrCode::with_error_correction_level(self.to_uri(), EcLevel::M)
+            .map_err(|_| SecureError::QrRender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_scale_is_bounded() {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "alice").with_issuer("Example");
+
+        assert_eq!(totp.to_qr_png(0), Err(SecureError::InvalidScale));
+        assert_eq!(
+            totp.to_qr_png(MAX_PNG_SCALE + 1),
+            Err(SecureError::InvalidScale)
+        );
+        assert_eq!(totp.to_qr_png(u32::MAX), Err(SecureError::InvalidScale));
+
+        let png = totp.to_qr_png(MAX_PNG_SCALE).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+}
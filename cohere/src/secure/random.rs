@@ -0,0 +1,57 @@
+use base32::Alphabet::Rfc4648;
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+use super::SecureError;
+
+/// Shortest secret accepted by [`generate_secret`], as required by RFC 4226.
+const MIN_SECRET_BYTES: usize = 16;
+/// Longest secret accepted by [`generate_secret`], one SHA-512 block. HMAC
+/// hashes longer keys down first, so more bytes add nothing.
+const MAX_SECRET_BYTES: usize = 128;
+
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), SecureError> {
+    SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| SecureError::Random)
+}
+
+/// Generate a base32 encoded shared secret of `bytes` random bytes, suitable
+/// for [`Totp`](super::totp::Totp) enrollment. `bytes` must be between 16
+/// and 128.
+pub fn generate_secret(bytes: usize) -> Result<String, SecureError> {
+    if !(MIN_SECRET_BYTES..=MAX_SECRET_BYTES).contains(&bytes) {
+        return Err(SecureError::InvalidSecret);
+    }
+
+    let mut key = Zeroizing::new(vec![0u8; bytes]);
+    fill_random(&mut key)?;
+
+    Ok(base32::encode(Rfc4648 { padding: false }, &key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_length_is_bounded() {
+        assert!(matches!(
+            generate_secret(15),
+            Err(SecureError::InvalidSecret)
+        ));
+        assert!(matches!(
+            generate_secret(129),
+            Err(SecureError::InvalidSecret)
+        ));
+        assert!(matches!(
+            generate_secret(usize::MAX),
+            Err(SecureError::InvalidSecret)
+        ));
+
+        let secret = generate_secret(20).unwrap();
+        let decoded = base32::decode(Rfc4648 { padding: false }, &secret).unwrap();
+        assert_eq!(decoded.len(), 20);
+        assert_eq!(generate_secret(128).unwrap().len(), 205);
+    }
+}
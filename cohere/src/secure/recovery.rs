@@ -0,0 +1,200 @@
+use base32::Alphabet::Rfc4648;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{SecureError, constant_time_eq, random::fill_random};
+
+/// Crockford base32, which avoids letters that are easily confused when
+/// codes are read from paper.
+const CODE_ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+const CODE_LENGTH: usize = 10;
+const SALT_LENGTH: usize = 16;
+
+/// Salted hashes of the recovery codes that have not been used yet.
+///
+/// This is the value to persist; the plaintext codes are only returned once
+/// by [`generate_recovery_codes`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecoveryCodes {
+    hashes: Vec<String>,
+}
+
+/// Generate `count` one-time recovery codes, returning them in plaintext
+/// alongside the hashes to store.
+pub fn generate_recovery_codes(count: usize) -> Result<(Vec<String>, RecoveryCodes), SecureError> {
+    let mut codes = Vec::with_capacity(count);
+    let mut hashes = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let mut random = [0u8; CODE_LENGTH];
+        fill_random(&mut random)?;
+        let code: String = random
+            .iter()
+            .map(|b| CODE_ALPHABET[(b & 0x1f) as usize] as char)
+            .collect();
+
+        let mut salt = [0u8; SALT_LENGTH];
+        fill_random(&mut salt)?;
+        hashes.push(format!(
+            "{}${}",
+            base32::encode(Rfc4648 { padding: false }, &salt),
+            base32::encode(Rfc4648 { padding: false }, &hash_code(&salt, &code)),
+        ));
+
+        codes.push(format!(
+            "{}-{}",
+            &code[..CODE_LENGTH / 2],
+            &code[CODE_LENGTH / 2..]
+        ));
+    }
+
+    Ok((codes, RecoveryCodes { hashes }))
+}
+
+impl RecoveryCodes {
+    pub fn from_hashes(hashes: Vec<String>) -> Self {
+        RecoveryCodes { hashes }
+    }
+
+    pub fn hashes(&self) -> &[String] {
+        &self.hashes
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Check the code against every stored hash and remove the matching one,
+    /// so each code can only be used once.
+    pub fn verify(&mut self, code: &str) -> Result<(), SecureError> {
+        let code = normalize_code(code).ok_or(SecureError::InvalidCode)?;
+
+        let mut matched = None;
+        for (index, stored) in self.hashes.iter().enumerate() {
+            let Some((salt, hash)) = stored.split_once('$') else {
+                continue;
+            };
+            let (Some(salt), Some(hash)) = (
+                base32::decode(Rfc4648 { padding: false }, salt),
+                base32::decode(Rfc4648 { padding: false }, hash),
+            ) else {
+                continue;
+            };
+            if constant_time_eq(&hash_code(&salt, &code), &hash) && matched.is_none() {
+                matched = Some(index);
+            }
+        }
+
+        match matched {
+            Some(index) => {
+                self.hashes.remove(index);
+                Ok(())
+            }
+            None => Err(SecureError::InvalidCode),
+        }
+    }
+}
+
+fn hash_code(salt: &[u8], code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Lowercase the code, drop separators and map look-alike characters the
+/// way Crockford base32 does.
+fn normalize_code(code: &str) -> Option<String> {
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_lowercase() {
+            'o' => '0',
+            'i' | 'l' => '1',
+            c => c,
+        })
+        .collect();
+
+    if code.len() == CODE_LENGTH && code.bytes().all(|b| CODE_ALPHABET.contains(&b)) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_grouped_crockford_base32() {
+        let (codes, stored) = generate_recovery_codes(8).unwrap();
+        assert_eq!(codes.len(), 8);
+        assert_eq!(stored.remaining(), 8);
+        for code in &codes {
+            let (first, second) = code.split_once('-').unwrap();
+            assert_eq!(first.len(), CODE_LENGTH / 2);
+            assert_eq!(second.len(), CODE_LENGTH / 2);
+            assert!(
+                code.bytes()
+                    .all(|b| b == b'-' || CODE_ALPHABET.contains(&b))
+            );
+        }
+        // Hashes are salted, so the stored values never repeat the codes.
+        for hash in stored.hashes() {
+            assert!(codes.iter().all(|code| !hash.contains(code.as_str())));
+        }
+    }
+
+    #[test]
+    fn codes_are_normalized_before_verifying() {
+        let (codes, mut stored) = generate_recovery_codes(3).unwrap();
+
+        let spaced = format!("  {}\n", codes[0].to_uppercase());
+        stored.verify(&spaced).unwrap();
+
+        let unseparated = codes[1].replace('-', " ");
+        stored.verify(&unseparated).unwrap();
+
+        // Crockford treats o, i and l as 0, 1 and 1.
+        let look_alike = codes[2].replace('0', "O").replace('1', "l");
+        stored.verify(&look_alike).unwrap();
+        assert_eq!(stored.remaining(), 0);
+    }
+
+    #[test]
+    fn codes_are_single_use() {
+        let (codes, mut stored) = generate_recovery_codes(2).unwrap();
+        stored.verify(&codes[0]).unwrap();
+        assert!(matches!(
+            stored.verify(&codes[0]),
+            Err(SecureError::InvalidCode)
+        ));
+        assert_eq!(stored.remaining(), 1);
+        stored.verify(&codes[1]).unwrap();
+    }
+
+    #[test]
+    fn wrong_codes_are_rejected() {
+        let (codes, mut stored) = generate_recovery_codes(2).unwrap();
+        let (other, _) = generate_recovery_codes(1).unwrap();
+
+        for wrong in [
+            other[0].as_str(),
+            "",
+            "abcde",
+            "abcde-fghjkm",
+            "uuuuu-uuuuu",
+        ] {
+            assert!(matches!(
+                stored.verify(wrong),
+                Err(SecureError::InvalidCode)
+            ));
+        }
+        // A persisted value that is not a salt and hash is skipped.
+        let mut corrupted = RecoveryCodes::from_hashes(vec!["garbage".into()]);
+        assert!(corrupted.verify(&codes[0]).is_err());
+        assert_eq!(stored.remaining(), 2);
+    }
+}
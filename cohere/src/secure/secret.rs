@@ -0,0 +1,214 @@
+#[cfg(unix)]
+use once_cell::sync::Lazy;
+#[cfg(unix)]
+use parking_lot::Mutex;
+#[cfg(unix)]
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use zeroize::Zeroize;
+
+use super::constant_time_eq;
+
+/// A secret value, such as a password or key read from the environment.
+///
+/// The value is wiped from memory when dropped and never shows up in
+/// `Debug` output. It deserializes like the value it wraps but cannot be
+/// serialized, so it can be a field of an [`env::parse`](crate::env::parse)
+/// config without leaking into logs or responses.
+pub struct Secret<T: Zeroize> {
+    value: T,
+    /// Start and length of the memory locked by [`Secret::lock_memory`].
+    locked: Option<(usize, usize)>,
+}
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret {
+            value,
+            locked: None,
+        }
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+
+    /// Changing the length of a locked value may move it out of the locked
+    /// memory.
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// Values whose bytes live on the heap, so they stay in place when the
+/// [`Secret`] holding them moves.
+pub trait HeapBytes: Zeroize {
+    fn bytes(&self) -> &[u8];
+}
+
+impl HeapBytes for String {
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl HeapBytes for Vec<u8> {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<T: HeapBytes> Secret<T> {
+    /// Ask the OS to keep the value out of swap and core dumps, returning
+    /// whether it did. Locking is limited by `RLIMIT_MEMLOCK`, applies to
+    /// whole pages, and is only supported on unix. Pages shared with other
+    /// locked secrets stay locked until the last of them is dropped.
+    pub fn lock_memory(&mut self) -> bool {
+        if self.locked.is_none() {
+            self.locked = lock(self.value.bytes());
+        }
+        self.locked.is_some()
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.value.zeroize();
+        if let Some(region) = self.locked.take() {
+            unlock(region);
+        }
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+/// Clones are not locked, even if the original is.
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret::new(self.value.clone())
+    }
+}
+
+impl<T: Zeroize + Default> Default for Secret<T> {
+    fn default() -> Self {
+        Secret::new(T::default())
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret::new(value)
+    }
+}
+
+/// Compares in constant time.
+impl<T: HeapBytes> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.value.bytes(), other.value.bytes())
+    }
+}
+
+impl<T: HeapBytes> Eq for Secret<T> {}
+
+impl<'de, T: Zeroize + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// How many secrets hold each locked page, keyed by page address. Locks
+/// do not nest, so a page is only unlocked once no secret is left on it.
+#[cfg(unix)]
+static LOCKED_PAGES: Lazy<Mutex<FxHashMap<usize, usize>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+#[cfg(unix)]
+fn lock(bytes: &[u8]) -> Option<(usize, usize)> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let page = page_size();
+    let start = bytes.as_ptr() as usize & !(page - 1);
+    let len = bytes.as_ptr() as usize + bytes.len() - start;
+
+    let mut pages = LOCKED_PAGES.lock();
+    // SAFETY: the range covers the pages holding `bytes`, which are mapped.
+    if unsafe { libc::mlock(start as *const libc::c_void, len) } != 0 {
+        return None;
+    }
+    for address in (start..start + len).step_by(page) {
+        *pages.entry(address).or_default() += 1;
+    }
+    Some((start, len))
+}
+
+#[cfg(unix)]
+fn unlock((start, len): (usize, usize)) {
+    let page = page_size();
+    let mut pages = LOCKED_PAGES.lock();
+    for address in (start..start + len).step_by(page) {
+        let Some(count) = pages.get_mut(&address) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            pages.remove(&address);
+            // SAFETY: the page was locked by `lock` and is still mapped, since
+            // the value is only freed after this returns.
+            unsafe {
+                libc::munlock(address as *const libc::c_void, page);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_bytes: &[u8]) -> Option<(usize, usize)> {
+    None
+}
+
+#[cfg(not(unix))]
+fn unlock(_region: (usize, usize)) {}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_page_stays_locked_until_last_secret_unlocks() {
+        let page = page_size();
+        let buffer = vec![0u8; 2 * page];
+        // Two values on the same page, as the allocator may place two
+        // secrets.
+        let offset = page - (buffer.as_ptr() as usize % page);
+        let (first, second) = buffer[offset..offset + 32].split_at(16);
+        let address = first.as_ptr() as usize;
+        let count = || LOCKED_PAGES.lock().get(&address).copied();
+
+        let Some(first) = lock(first) else {
+            // RLIMIT_MEMLOCK may be zero where the tests run.
+            return;
+        };
+        let second = lock(second).unwrap();
+        assert_eq!(count(), Some(2));
+
+        unlock(first);
+        assert_eq!(count(), Some(1));
+        unlock(second);
+        assert_eq!(count(), None);
+    }
+}
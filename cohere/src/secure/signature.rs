@@ -0,0 +1,453 @@
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use dashmap::{DashMap, mapref::entry::Entry};
+use ring::{digest, hmac};
+use rustc_hash::FxHashMap;
+use std::{fmt, time::Duration};
+
+use super::{SecureError, constant_time_eq, random::fill_random};
+use crate::time::now;
+
+pub const CONTENT_DIGEST_HEADER: &str = "content-digest";
+pub const SIGNATURE_INPUT_HEADER: &str = "signature-input";
+pub const SIGNATURE_HEADER: &str = "signature";
+
+const LABEL: &str = "sig1";
+const ALGORITHM: &str = "hmac-sha256";
+const COMPONENTS: &str = r#"("@method" "@path" "@query" "content-digest")"#;
+
+/// Expired nonces are purged once the cache holds this many, rather than
+/// on every request.
+const NONCE_PURGE_THRESHOLD: usize = 4096;
+
+/// The parts of an HTTP request covered by the signature.
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+/// Header values carrying a signature, as described by HTTP Message
+/// Signatures (RFC 9421) and Digest Fields (RFC 9530).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureHeaders {
+    pub content_digest: String,
+    pub signature_input: String,
+    pub signature: String,
+}
+
+/// Signs requests with a shared HMAC-SHA256 key.
+pub struct RequestSigner {
+    key_id: String,
+    key: hmac::Key,
+}
+
+impl fmt::Debug for RequestSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestSigner")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RequestSigner {
+    pub fn new(key_id: impl Into<String>, secret: &[u8]) -> Result<Self, SecureError> {
+        Ok(RequestSigner {
+            key_id: key_id.into(),
+            key: hmac_key(secret)?,
+        })
+    }
+
+    pub fn sign(&self, req: &SignedRequest<'_>) -> Result<SignatureHeaders, SecureError> {
+        self.sign_at(req, now())
+    }
+
+    fn sign_at(
+        &self,
+        req: &SignedRequest<'_>,
+        created: u64,
+    ) -> Result<SignatureHeaders, SecureError> {
+        let mut nonce = [0u8; 16];
+        fill_random(&mut nonce)?;
+
+        let content_digest = content_digest(req.body);
+        let params = format!(
+            r#"{};created={};keyid="{}";alg="{}";nonce="{}""#,
+            COMPONENTS,
+            created,
+            self.key_id,
+            ALGORITHM,
+            URL_SAFE_NO_PAD.encode(nonce),
+        );
+        let tag = hmac::sign(
+            &self.key,
+            signature_base(req, &content_digest, &params).as_bytes(),
+        );
+
+        Ok(SignatureHeaders {
+            content_digest,
+            signature_input: format!("{}={}", LABEL, params),
+            signature: format!("{}=:{}:", LABEL, STANDARD.encode(tag.as_ref())),
+        })
+    }
+}
+
+/// Verifies signed requests, rejecting stale timestamps and replays.
+pub struct SignatureVerifier {
+    keys: FxHashMap<String, hmac::Key>,
+    tolerance: Duration,
+    seen: DashMap<String, u64>,
+}
+
+impl SignatureVerifier {
+    /// Accept signatures created at most `tolerance` away from the local clock.
+    pub fn new(tolerance: Duration) -> Self {
+        SignatureVerifier {
+            keys: FxHashMap::default(),
+            tolerance,
+            seen: DashMap::new(),
+        }
+    }
+
+    pub fn with_key(
+        mut self,
+        key_id: impl Into<String>,
+        secret: &[u8],
+    ) -> Result<Self, SecureError> {
+        self.keys.insert(key_id.into(), hmac_key(secret)?);
+        Ok(self)
+    }
+
+    /// Verify the request and return the id of the key that signed it.
+    pub fn verify(
+        &self,
+        req: &SignedRequest<'_>,
+        headers: &SignatureHeaders,
+    ) -> Result<String, SecureError> {
+        let params = headers
+            .signature_input
+            .strip_prefix(LABEL)
+            .and_then(|v| v.strip_prefix('='))
+            .ok_or(SecureError::InvalidSignature)?;
+        let tag = headers
+            .signature
+            .strip_prefix(LABEL)
+            .and_then(|v| v.strip_prefix("=:"))
+            .and_then(|v| v.strip_suffix(':'))
+            .and_then(|v| STANDARD.decode(v).ok())
+            .ok_or(SecureError::InvalidSignature)?;
+
+        let parsed = parse_params(params)?;
+        if parsed.alg != ALGORITHM {
+            return Err(SecureError::InvalidSignature);
+        }
+        let key = self.keys.get(parsed.keyid).ok_or(SecureError::UnknownKey)?;
+
+        if !constant_time_eq(
+            content_digest(req.body).as_bytes(),
+            headers.content_digest.as_bytes(),
+        ) {
+            return Err(SecureError::InvalidSignature);
+        }
+        hmac::verify(
+            key,
+            signature_base(req, &headers.content_digest, params).as_bytes(),
+            &tag,
+        )
+        .map_err(|_| SecureError::InvalidSignature)?;
+
+        let now = now();
+        let tolerance = self.tolerance.as_secs();
+        if parsed.created.abs_diff(now) > tolerance {
+            return Err(SecureError::Expired);
+        }
+
+        if self.seen.len() >= NONCE_PURGE_THRESHOLD {
+            self.seen.retain(|_, expires_at| *expires_at > now);
+        }
+        let replay_key = format!("{}:{}", parsed.keyid, parsed.nonce);
+        match self.seen.entry(replay_key) {
+            Entry::Occupied(_) => Err(SecureError::Replayed),
+            Entry::Vacant(entry) => {
+                entry.insert(parsed.created.saturating_add(tolerance));
+                Ok(parsed.keyid.to_owned())
+            }
+        }
+    }
+}
+
+struct SignatureParams<'a> {
+    created: u64,
+    keyid: &'a str,
+    alg: &'a str,
+    nonce: &'a str,
+}
+
+/// Parse the parameters following the covered components. Only signatures
+/// covering exactly the components produced by [`RequestSigner`], followed
+/// by nothing but `;name=value` parameters, are accepted.
+fn parse_params(params: &str) -> Result<SignatureParams<'_>, SecureError> {
+    let rest = params
+        .strip_prefix(COMPONENTS)
+        .and_then(|rest| rest.strip_prefix(';'))
+        .ok_or(SecureError::InvalidSignature)?;
+
+    let (mut created, mut keyid, mut alg, mut nonce) = (None, None, None, None);
+    for param in rest.split(';') {
+        let (name, value) = param.split_once('=').ok_or(SecureError::InvalidSignature)?;
+        let slot = match name {
+            "created" => {
+                let value = value
+                    .parse::<u64>()
+                    .map_err(|_| SecureError::InvalidSignature)?;
+                if created.replace(value).is_some() {
+                    return Err(SecureError::InvalidSignature);
+                }
+                continue;
+            }
+            "keyid" => &mut keyid,
+            "alg" => &mut alg,
+            "nonce" => &mut nonce,
+            _ if is_param_name(name) => continue,
+            _ => return Err(SecureError::InvalidSignature),
+        };
+        let unquoted = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .filter(|v| !v.contains(['"', '\\']))
+            .ok_or(SecureError::InvalidSignature)?;
+        if slot.replace(unquoted).is_some() {
+            return Err(SecureError::InvalidSignature);
+        }
+    }
+
+    match (created, keyid, alg, nonce) {
+        (Some(created), Some(keyid), Some(alg), Some(nonce)) => Ok(SignatureParams {
+            created,
+            keyid,
+            alg,
+            nonce,
+        }),
+        _ => Err(SecureError::InvalidSignature),
+    }
+}
+
+/// Structured field keys: a lowercase letter or `*`, then lowercase letters,
+/// digits, `_`, `-`, `.` and `*`.
+fn is_param_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c == '*')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_-.*".contains(&b))
+}
+
+fn signature_base(req: &SignedRequest<'_>, content_digest: &str, params: &str) -> String {
+    format!(
+        "\"@method\": {}\n\"@path\": {}\n\"@query\": ?{}\n\"content-digest\": {}\n\"@signature-params\": {}",
+        req.method.to_uppercase(),
+        req.path,
+        req.query.unwrap_or_default(),
+        content_digest,
+        params,
+    )
+}
+
+fn content_digest(body: &[u8]) -> String {
+    format!(
+        "sha-256=:{}:",
+        STANDARD.encode(digest::digest(&digest::SHA256, body))
+    )
+}
+
+fn hmac_key(secret: &[u8]) -> Result<hmac::Key, SecureError> {
+    if secret.len() < 32 {
+        return Err(SecureError::InvalidKey);
+    }
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = &[9; 32];
+
+    fn signer() -> RequestSigner {
+        RequestSigner::new("client", SECRET).unwrap()
+    }
+
+    fn verifier() -> SignatureVerifier {
+        SignatureVerifier::new(Duration::from_secs(30))
+            .with_key("client", SECRET)
+            .unwrap()
+    }
+
+    fn request() -> SignedRequest<'static> {
+        SignedRequest {
+            method: "POST",
+            path: "/orders",
+            query: Some("dry_run=true"),
+            body: br#"{"amount":10}"#,
+        }
+    }
+
+    #[test]
+    fn signed_request_verifies_once() {
+        let verifier = verifier();
+        let headers = signer().sign(&request()).unwrap();
+        assert!(headers.signature_input.starts_with(r#"sig1=("@method""#));
+        assert_eq!(verifier.verify(&request(), &headers).unwrap(), "client");
+        assert_eq!(
+            verifier.verify(&request(), &headers),
+            Err(SecureError::Replayed)
+        );
+        // A fresh signature of the same request has a new nonce.
+        let again = signer().sign(&request()).unwrap();
+        verifier.verify(&request(), &again).unwrap();
+    }
+
+    #[test]
+    fn covered_components_cannot_change() {
+        let headers = signer().sign(&request()).unwrap();
+        let tampered = [
+            SignedRequest {
+                method: "PUT",
+                ..request()
+            },
+            SignedRequest {
+                path: "/admin",
+                ..request()
+            },
+            SignedRequest {
+                query: Some("dry_run=false"),
+                ..request()
+            },
+            SignedRequest {
+                query: None,
+                ..request()
+            },
+            SignedRequest {
+                body: br#"{"amount":1000}"#,
+                ..request()
+            },
+        ];
+        for req in tampered {
+            assert_eq!(
+                verifier().verify(&req, &headers),
+                Err(SecureError::InvalidSignature)
+            );
+        }
+
+        // Swapping in the digest of another body does not help either.
+        let other = signer()
+            .sign(&SignedRequest {
+                body: b"other",
+                ..request()
+            })
+            .unwrap();
+        let mixed = SignatureHeaders {
+            content_digest: other.content_digest,
+            ..headers.clone()
+        };
+        assert_eq!(
+            verifier().verify(
+                &SignedRequest {
+                    body: b"other",
+                    ..request()
+                },
+                &mixed
+            ),
+            Err(SecureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn signature_params_cannot_change() {
+        let headers = signer().sign(&request()).unwrap();
+        let retimed = SignatureHeaders {
+            signature_input: headers
+                .signature_input
+                .replacen(";created=", ";created=1", 1),
+            ..headers.clone()
+        };
+        assert!(verifier().verify(&request(), &retimed).is_err());
+
+        let other_key = RequestSigner::new("client", &[8; 32])
+            .unwrap()
+            .sign(&request())
+            .unwrap();
+        assert_eq!(
+            verifier().verify(&request(), &other_key),
+            Err(SecureError::InvalidSignature)
+        );
+        let unknown = RequestSigner::new("stranger", SECRET)
+            .unwrap()
+            .sign(&request())
+            .unwrap();
+        assert_eq!(
+            verifier().verify(&request(), &unknown),
+            Err(SecureError::UnknownKey)
+        );
+    }
+
+    #[test]
+    fn created_must_be_within_tolerance() {
+        let signer = signer();
+        let verifier = verifier();
+        for offset in [-20i64, 20] {
+            let created = now().saturating_add_signed(offset);
+            let headers = signer.sign_at(&request(), created).unwrap();
+            verifier.verify(&request(), &headers).unwrap();
+        }
+        for offset in [-40i64, 40] {
+            let created = now().saturating_add_signed(offset);
+            let headers = signer.sign_at(&request(), created).unwrap();
+            assert_eq!(
+                verifier.verify(&request(), &headers),
+                Err(SecureError::Expired)
+            );
+        }
+    }
+
+    #[test]
+    fn nonces_are_purged_once_expired() {
+        let verifier = verifier();
+        for i in 0..NONCE_PURGE_THRESHOLD {
+            verifier.seen.insert(format!("client:{}", i), now() - 1);
+        }
+        verifier.seen.insert("client:live".into(), now() + 60);
+        let headers = signer().sign(&request()).unwrap();
+        verifier.verify(&request(), &headers).unwrap();
+        assert_eq!(verifier.seen.len(), 2);
+        assert!(verifier.seen.contains_key("client:live"));
+    }
+
+    #[test]
+    fn malformed_params_are_rejected() {
+        let valid = r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";alg="hmac-sha256";nonce="n""#;
+        assert!(parse_params(valid).is_ok());
+        let extended = format!("{};expires=2;tag=\"app\"", valid);
+        assert!(parse_params(&extended).is_ok());
+
+        for malformed in [
+            // Anything but parameters after the component list.
+            r#"("@method" "@path" "@query" "content-digest") garbage;created=1;keyid="k";alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path" "@query" "content-digest")"extra";created=1;keyid="k";alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";alg="hmac-sha256";nonce="n";"#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";alg="hmac-sha256";nonce="n";flag"#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";alg="hmac-sha256";nonce="n";Bad=1"#,
+            // Missing, duplicated or malformed values.
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";alg="hmac-sha256""#,
+            r#"("@method" "@path" "@query" "content-digest");created=x;keyid="k";alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid=k;alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;keyid="k";keyid="j";alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path" "@query" "content-digest");created=1;created=2;keyid="k";alg="hmac-sha256";nonce="n""#,
+            r#"("@method" "@path");created=1;keyid="k";alg="hmac-sha256";nonce="n""#,
+        ] {
+            assert!(parse_params(malformed).is_err(), "{}", malformed);
+        }
+    }
+}
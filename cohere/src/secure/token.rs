@@ -0,0 +1,393 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use dashmap::{DashMap, mapref::entry::Entry};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::{fmt, future::Future, time::Duration};
+
+use super::{SecureError, random::fill_random};
+use crate::time::now;
+
+const EXPIRES_PARAM: &str = "expires";
+const SIGNATURE_PARAM: &str = "signature";
+
+/// Consumed ids are purged once the store holds this many, rather than on
+/// every request.
+const PURGE_THRESHOLD: usize = 4096;
+
+/// What a token was issued for, to whom and until when.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    #[serde(rename = "pur")]
+    pub purpose: String,
+    #[serde(rename = "sub")]
+    pub subject: String,
+    #[serde(rename = "exp")]
+    pub expires_at: u64,
+    /// Random id used to recognise a token once it has been consumed.
+    #[serde(rename = "jti")]
+    pub id: String,
+}
+
+/// Remembers consumed token ids until the tokens expire.
+pub trait ConsumedTokenStore: Send + Sync + 'static {
+    /// Mark `id` as consumed, returning `false` if it already was.
+    fn consume(
+        &self,
+        id: &str,
+        expires_at: u64,
+    ) -> impl Future<Output = Result<bool, SecureError>> + Send;
+}
+
+/// [`ConsumedTokenStore`] remembering consumed ids in memory until they
+/// expire. Behind a load balancer each instance would accept a token once,
+/// so use a shared store there.
+#[derive(Default)]
+pub struct MemoryConsumedTokenStore {
+    consumed: DashMap<String, u64>,
+}
+
+impl MemoryConsumedTokenStore {
+    pub fn new() -> Self {
+        MemoryConsumedTokenStore::default()
+    }
+}
+
+impl ConsumedTokenStore for MemoryConsumedTokenStore {
+    async fn consume(&self, id: &str, expires_at: u64) -> Result<bool, SecureError> {
+        if self.consumed.len() >= PURGE_THRESHOLD {
+            let now = now();
+            self.consumed.retain(|_, expires_at| *expires_at > now);
+        }
+        match self.consumed.entry(id.to_owned()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(expires_at);
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Mints and verifies URL-safe tokens for magic links, password resets and
+/// similar flows, and presigns URLs with an expiring signature.
+///
+/// Tokens are `payload.signature`, both base64url encoded, where the payload
+/// is the JSON encoded [`TokenClaims`] and the signature is HMAC-SHA256.
+pub struct TokenSigner {
+    key: hmac::Key,
+}
+
+impl fmt::Debug for TokenSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenSigner").finish_non_exhaustive()
+    }
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> Result<Self, SecureError> {
+        if secret.len() < 32 {
+            return Err(SecureError::InvalidKey);
+        }
+        Ok(TokenSigner {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        })
+    }
+
+    pub fn issue(
+        &self,
+        purpose: &str,
+        subject: &str,
+        ttl: Duration,
+    ) -> Result<String, SecureError> {
+        let mut id = [0u8; 16];
+        fill_random(&mut id)?;
+
+        let claims = TokenClaims {
+            purpose: purpose.to_owned(),
+            subject: subject.to_owned(),
+            expires_at: now().saturating_add(ttl.as_secs()),
+            id: URL_SAFE_NO_PAD.encode(id),
+        };
+        let payload = serde_json::to_vec(&claims).map_err(|_| SecureError::InvalidToken)?;
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let tag = self.sign("token", &payload);
+
+        Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag)))
+    }
+
+    /// Check the signature, purpose and expiry of a token. The token may be
+    /// used again; see [`consume`](Self::consume) for single-use tokens.
+    pub fn verify(&self, token: &str, purpose: &str) -> Result<TokenClaims, SecureError> {
+        let (payload, tag) = token.split_once('.').ok_or(SecureError::InvalidToken)?;
+        let tag = URL_SAFE_NO_PAD
+            .decode(tag)
+            .map_err(|_| SecureError::InvalidToken)?;
+        self.check("token", payload, &tag)?;
+
+        let claims: TokenClaims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(SecureError::InvalidToken)?;
+        if claims.purpose != purpose {
+            return Err(SecureError::InvalidClaim("purpose"));
+        }
+        if now() >= claims.expires_at {
+            return Err(SecureError::Expired);
+        }
+        Ok(claims)
+    }
+
+    /// Verify a single-use token and record it in `store`, rejecting it if it
+    /// was consumed before.
+    pub async fn consume<S: ConsumedTokenStore>(
+        &self,
+        store: &S,
+        token: &str,
+        purpose: &str,
+    ) -> Result<TokenClaims, SecureError> {
+        let claims = self.verify(token, purpose)?;
+        if !store.consume(&claims.id, claims.expires_at).await? {
+            return Err(SecureError::Replayed);
+        }
+        Ok(claims)
+    }
+
+    /// Append `expires` and `signature` query parameters to `url`, which may
+    /// be absolute or just a path. Only the path and query are signed, so the
+    /// link keeps working behind a different host.
+    pub fn presign_url(&self, url: &str, ttl: Duration) -> String {
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let unsigned = format!(
+            "{}{}{}={}",
+            url,
+            separator,
+            EXPIRES_PARAM,
+            now().saturating_add(ttl.as_secs())
+        );
+        let tag = self.sign("url", &path_and_query(&unsigned));
+
+        let mut signed = format!(
+            "{}&{}={}",
+            unsigned,
+            SIGNATURE_PARAM,
+            URL_SAFE_NO_PAD.encode(tag)
+        );
+        if let Some(fragment) = fragment {
+            signed.push('#');
+            signed.push_str(fragment);
+        }
+        signed
+    }
+
+    /// Verify a URL produced by [`presign_url`](Self::presign_url), given
+    /// the path and query of the incoming request.
+    pub fn verify_url(&self, path_and_query: &str) -> Result<(), SecureError> {
+        let (unsigned, tag) = path_and_query
+            .rsplit_once(&format!("&{}=", SIGNATURE_PARAM))
+            .ok_or(SecureError::InvalidSignature)?;
+        let tag = URL_SAFE_NO_PAD
+            .decode(tag)
+            .map_err(|_| SecureError::InvalidSignature)?;
+        self.check("url", unsigned, &tag)?;
+
+        let expires_at = unsigned
+            .split_once('?')
+            .and_then(|(_, query)| {
+                query.rsplit('&').find_map(|param| {
+                    param
+                        .strip_prefix(EXPIRES_PARAM)
+                        .and_then(|v| v.strip_prefix('='))
+                })
+            })
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or(SecureError::InvalidSignature)?;
+        if now() >= expires_at {
+            return Err(SecureError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Sign `message` under a context label so a token signature can never
+    /// be replayed as a URL signature or the other way round.
+    fn sign(&self, context: &str, message: &str) -> hmac::Tag {
+        hmac::sign(&self.key, format!("{}\n{}", context, message).as_bytes())
+    }
+
+    fn check(&self, context: &str, message: &str, tag: &[u8]) -> Result<(), SecureError> {
+        hmac::verify(
+            &self.key,
+            format!("{}\n{}", context, message).as_bytes(),
+            tag,
+        )
+        .map_err(|_| SecureError::InvalidSignature)
+    }
+}
+
+/// Strip the scheme and authority from an absolute URL.
+fn path_and_query(url: &str) -> String {
+    let Some((_, rest)) = url.split_once("://") else {
+        return url.to_owned();
+    };
+    match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => rest[i..].to_owned(),
+        Some(i) => format!("/{}", &rest[i..]),
+        None => "/".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn signer() -> TokenSigner {
+        TokenSigner::new(&[3; 32]).unwrap()
+    }
+
+    #[test]
+    fn issued_token_verifies_for_its_purpose() {
+        let signer = signer();
+        let token = signer.issue("reset", "user-1", HOUR).unwrap();
+        let claims = signer.verify(&token, "reset").unwrap();
+        assert_eq!(claims.purpose, "reset");
+        assert_eq!(claims.subject, "user-1");
+        assert!(claims.expires_at > now());
+        // Verifying does not use the token up.
+        assert_eq!(signer.verify(&token, "reset").unwrap(), claims);
+
+        assert_eq!(
+            signer.verify(&token, "login"),
+            Err(SecureError::InvalidClaim("purpose"))
+        );
+        assert_eq!(
+            TokenSigner::new(&[4; 32]).unwrap().verify(&token, "reset"),
+            Err(SecureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let signer = signer();
+        let token = signer.issue("reset", "user-1", Duration::ZERO).unwrap();
+        assert_eq!(signer.verify(&token, "reset"), Err(SecureError::Expired));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let signer = signer();
+        let token = signer.issue("reset", "user-1", HOUR).unwrap();
+        let (payload, tag) = token.split_once('.').unwrap();
+
+        let mut claims = signer.verify(&token, "reset").unwrap();
+        claims.subject = "admin".into();
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        assert_eq!(
+            signer.verify(&format!("{}.{}", forged, tag), "reset"),
+            Err(SecureError::InvalidSignature)
+        );
+
+        let mut mac = URL_SAFE_NO_PAD.decode(tag).unwrap();
+        mac[0] ^= 1;
+        let tampered = format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac));
+        assert_eq!(
+            signer.verify(&tampered, "reset"),
+            Err(SecureError::InvalidSignature)
+        );
+        for malformed in ["", payload, &format!("{}.!!", payload)] {
+            assert!(signer.verify(malformed, "reset").is_err());
+        }
+    }
+
+    #[test]
+    fn url_signature_is_not_a_token_signature() {
+        let signer = signer();
+        let token = signer.issue("reset", "user-1", HOUR).unwrap();
+        let (payload, tag) = token.split_once('.').unwrap();
+        assert_eq!(
+            signer.verify_url(&format!("{}&signature={}", payload, tag)),
+            Err(SecureError::InvalidSignature)
+        );
+    }
+
+    #[tokio::test]
+    async fn consumed_token_cannot_be_replayed() {
+        let signer = signer();
+        let store = MemoryConsumedTokenStore::new();
+        let token = signer.issue("login", "user-1", HOUR).unwrap();
+        let other = signer.issue("login", "user-1", HOUR).unwrap();
+
+        signer.consume(&store, &token, "login").await.unwrap();
+        assert_eq!(
+            signer.consume(&store, &token, "login").await,
+            Err(SecureError::Replayed)
+        );
+        signer.consume(&store, &other, "login").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn consumed_ids_are_purged_once_expired() {
+        let store = MemoryConsumedTokenStore::new();
+        for i in 0..PURGE_THRESHOLD {
+            store.consumed.insert(format!("id-{}", i), now() - 1);
+        }
+        store.consumed.insert("live".into(), now() + 60);
+        assert!(store.consume("new", now() + 60).await.unwrap());
+        assert_eq!(store.consumed.len(), 2);
+        assert!(!store.consume("live", now() + 60).await.unwrap());
+    }
+
+    #[test]
+    fn presigned_url_verifies_by_path_and_query() {
+        let signer = signer();
+        let signed =
+            signer.presign_url("https://files.example.com/a/b.pdf?download=1#page=2", HOUR);
+        assert!(signed.starts_with("https://files.example.com/a/b.pdf?download=1&expires="));
+        assert!(signed.ends_with("#page=2"));
+
+        let (path_and_query, _) = signed
+            .strip_prefix("https://files.example.com")
+            .unwrap()
+            .split_once('#')
+            .unwrap();
+        signer.verify_url(path_and_query).unwrap();
+
+        let relative = signer.presign_url("/reports", HOUR);
+        assert!(relative.starts_with("/reports?expires="));
+        signer.verify_url(&relative).unwrap();
+    }
+
+    #[test]
+    fn altered_url_is_rejected() {
+        let signer = signer();
+        let signed = signer.presign_url("/files/a.pdf?user=1", HOUR);
+        let (unsigned, tag) = signed.rsplit_once("&signature=").unwrap();
+        let expires = unsigned.rsplit_once("&expires=").unwrap().1;
+
+        for altered in [
+            signed.replace("/files/a.pdf", "/files/b.pdf"),
+            signed.replace("user=1", "user=2"),
+            signed.replace("?user=1", "?user=1&admin=1"),
+            format!("{}&admin=1", signed),
+            // The same parameters in another order.
+            format!("/files/a.pdf?expires={}&user=1&signature={}", expires, tag),
+            // A later expiry.
+            signed.replace(&format!("expires={}", expires), "expires=99999999999"),
+            unsigned.to_owned(),
+        ] {
+            assert!(signer.verify_url(&altered).is_err(), "{}", altered);
+        }
+    }
+
+    #[test]
+    fn expired_url_is_rejected() {
+        let signer = signer();
+        let signed = signer.presign_url("/files/a.pdf", Duration::ZERO);
+        assert_eq!(signer.verify_url(&signed), Err(SecureError::Expired));
+    }
+}
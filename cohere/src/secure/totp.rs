@@ -1,45 +1,475 @@
 use base32::Alphabet::Rfc4648;
 use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
 use sha1::Sha1;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-pub fn validate_totp(secret: &str, otp: &str, time_step: u64) -> Result<(), String> {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let counters = [now / time_step, (now / time_step) - 1];
-
-    for counter in counters.iter() {
-        match generate_totp(secret, *counter) {
-            Ok(generated_otp) => {
-                if generated_otp == otp {
-                    return Ok(());
-                }
-                continue;
+use sha2::{Sha256, Sha512};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use zeroize::Zeroizing;
+
+use super::{Secret, SecureError, constant_time_eq};
+
+const URI_PREFIX: &str = "otpauth://totp/";
+
+/// Characters left unescaped in labels and parameters of a Key URI.
+const URI_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Sha1 => f.write_str("SHA1"),
+            Algorithm::Sha256 => f.write_str("SHA256"),
+            Algorithm::Sha512 => f.write_str("SHA512"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = SecureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "SHA1" => Ok(Algorithm::Sha1),
+            "SHA256" => Ok(Algorithm::Sha256),
+            "SHA512" => Ok(Algorithm::Sha512),
+            _ => Err(SecureError::InvalidUri),
+        }
+    }
+}
+
+/// TOTP configuration for a single enrolled account, as carried by an
+/// `otpauth://` provisioning URI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Totp {
+    /// Base32 encoded shared secret.
+    pub secret: Secret<String>,
+    pub issuer: Option<String>,
+    pub account: String,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    /// Time step in seconds.
+    pub period: u64,
+}
+
+impl Totp {
+    /// Create a configuration with the defaults understood by every
+    /// authenticator app: SHA1, 6 digits and a 30 second period.
+    pub fn new(secret: impl Into<String>, account: impl Into<String>) -> Self {
+        Totp {
+            secret: Secret::new(secret.into()),
+            issuer: None,
+            account: account.into(),
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        }
+    }
+
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Build the `otpauth://totp/...` provisioning URI for this account.
+    pub fn to_uri(&self) -> String {
+        let account = utf8_percent_encode(&self.account, URI_ESCAPE);
+        let mut uri = match &self.issuer {
+            Some(issuer) => format!(
+                "{}{}:{}",
+                URI_PREFIX,
+                utf8_percent_encode(issuer, URI_ESCAPE),
+                account
+            ),
+            None => format!("{}{}", URI_PREFIX, account),
+        };
+
+        uri.push_str("?secret=");
+        uri.push_str(&Zeroizing::new(self.secret.expose().to_uppercase()));
+        if let Some(issuer) = &self.issuer {
+            uri.push_str("&issuer=");
+            uri.extend(utf8_percent_encode(issuer, URI_ESCAPE));
+        }
+        uri.push_str(&format!(
+            "&algorithm={}&digits={}&period={}",
+            self.algorithm, self.digits, self.period
+        ));
+        uri
+    }
+
+    /// Parse a provisioning URI produced by [`Totp::to_uri`] or by another
+    /// issuer following the Key URI Format.
+    pub fn from_uri(uri: &str) -> Result<Self, SecureError> {
+        let rest = uri
+            .strip_prefix(URI_PREFIX)
+            .ok_or(SecureError::InvalidUri)?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let label = percent_decode_str(label)
+            .decode_utf8()
+            .map_err(|_| SecureError::InvalidUri)?;
+        let (label_issuer, account) = match label.split_once(':') {
+            Some((issuer, account)) => (Some(issuer.to_owned()), account.trim_start()),
+            None => (None, label.as_ref()),
+        };
+        if account.is_empty() {
+            return Err(SecureError::InvalidUri);
+        }
+
+        let mut totp = Totp::new("", account);
+        totp.issuer = label_issuer;
+
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode_str(value)
+                .decode_utf8()
+                .map_err(|_| SecureError::InvalidUri)?;
+            match key {
+                "secret" => totp.secret = Secret::new(value.to_uppercase()),
+                // The Key URI Format requires the label issuer, when there
+                // is one, to match the parameter.
+                "issuer" => match &totp.issuer {
+                    Some(issuer) if *issuer != value => return Err(SecureError::InvalidUri),
+                    _ => totp.issuer = Some(value.into_owned()),
+                },
+                "algorithm" => totp.algorithm = value.parse()?,
+                "digits" => totp.digits = value.parse().map_err(|_| SecureError::InvalidUri)?,
+                "period" => totp.period = value.parse().map_err(|_| SecureError::InvalidUri)?,
+                _ => {}
+            }
+        }
+
+        if !(6..=8).contains(&totp.digits) || totp.period == 0 {
+            return Err(SecureError::InvalidUri);
+        }
+        decode_secret(totp.secret.expose())?;
+
+        Ok(totp)
+    }
+
+    /// Generate the code for the given time step counter.
+    pub fn generate(&self, counter: u64) -> Result<String, SecureError> {
+        if !(6..=8).contains(&self.digits) {
+            return Err(SecureError::InvalidCode);
+        }
+        let key = decode_secret(self.secret.expose())?;
+        let digest = hmac_digest(self.algorithm, &key, &counter.to_be_bytes())?;
+
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary_code = ((u32::from(digest[offset]) & 0x7f) << 24)
+            | ((u32::from(digest[offset + 1]) & 0xff) << 16)
+            | ((u32::from(digest[offset + 2]) & 0xff) << 8)
+            | (u32::from(digest[offset + 3]) & 0xff);
+
+        let modulus = 10u32.pow(self.digits);
+        Ok(format!(
+            "{:0width$}",
+            binary_code % modulus,
+            width = self.digits as usize
+        ))
+    }
+
+    /// Check the code against the current and the previous time step.
+    pub fn validate(&self, otp: &str) -> Result<(), SecureError> {
+        self.verify(otp).map(|_| ())
+    }
+
+    /// Check the code like [`Totp::validate`] and return the time step it
+    /// matched, so callers can reject a code that was already used.
+    pub fn verify(&self, otp: &str) -> Result<u64, SecureError> {
+        if self.period == 0 {
+            return Err(SecureError::InvalidCode);
+        }
+        if otp.len() != self.digits as usize || !otp.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(SecureError::InvalidCode);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| SecureError::InvalidCode)?
+            .as_secs();
+        self.verify_at(otp, now)
+    }
+
+    fn verify_at(&self, otp: &str, now: u64) -> Result<u64, SecureError> {
+        let counters = [now / self.period, (now / self.period).saturating_sub(1)];
+
+        let mut matched = None;
+        for counter in counters.iter() {
+            let generated_otp = self.generate(*counter)?;
+            if constant_time_eq(generated_otp.as_bytes(), otp.as_bytes()) && matched.is_none() {
+                matched = Some(*counter);
             }
-            Err(e) => return Err(e),
         }
+
+        matched.ok_or(SecureError::InvalidCode)
     }
-    Err(String::from("Invalid OTP"))
 }
 
-fn generate_totp(secret: &str, counter: u64) -> Result<String, String> {
-    let key = base32::decode(Rfc4648 { padding: false }, &secret.to_uppercase())
-        .ok_or("Invalid Base32 Error")?;
+impl FromStr for Totp {
+    type Err = SecureError;
 
-    let mut msg = [0u8; 8];
-    msg.copy_from_slice(&counter.to_be_bytes());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Totp::from_uri(s)
+    }
+}
+
+pub fn validate_totp(secret: &str, otp: &str, time_step: u64) -> Result<(), SecureError> {
+    let mut totp = Totp::new(secret, "");
+    totp.period = time_step;
+    totp.validate(otp)
+}
+
+fn decode_secret(secret: &str) -> Result<Zeroizing<Vec<u8>>, SecureError> {
+    let secret = Zeroizing::new(secret.trim_end_matches('=').to_uppercase());
+    base32::decode(Rfc4648 { padding: false }, &secret)
+        .map(Zeroizing::new)
+        .filter(|key| !key.is_empty())
+        .ok_or(SecureError::InvalidSecret)
+}
+
+fn hmac_digest(algorithm: Algorithm, key: &[u8], msg: &[u8]) -> Result<Vec<u8>, SecureError> {
+    fn digest<M: Mac + hmac::digest::KeyInit>(
+        key: &[u8],
+        msg: &[u8],
+    ) -> Result<Vec<u8>, SecureError> {
+        let mut mac = <M as Mac>::new_from_slice(key).map_err(|_| SecureError::InvalidSecret)?;
+        mac.update(msg);
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    match algorithm {
+        Algorithm::Sha1 => digest::<Hmac<Sha1>>(key, msg),
+        Algorithm::Sha256 => digest::<Hmac<Sha256>>(key, msg),
+        Algorithm::Sha512 => digest::<Hmac<Sha512>>(key, msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test times of RFC 6238 Appendix B.
+    const TIMES: [u64; 6] = [
+        59,
+        1111111109,
+        1111111111,
+        1234567890,
+        2000000000,
+        20000000000,
+    ];
+
+    fn rfc6238(algorithm: Algorithm, seed: &[u8]) -> Totp {
+        let mut totp = Totp::new(base32::encode(Rfc4648 { padding: false }, seed), "rfc6238");
+        totp.algorithm = algorithm;
+        totp.digits = 8;
+        totp
+    }
+
+    fn check(totp: &Totp, codes: [&str; 6]) {
+        for (time, code) in TIMES.into_iter().zip(codes) {
+            assert_eq!(totp.generate(time / 30).unwrap(), code, "time {}", time);
+            assert_eq!(totp.verify_at(code, time), Ok(time / 30), "time {}", time);
+        }
+    }
+
+    #[test]
+    fn rfc6238_sha1() {
+        let totp = rfc6238(Algorithm::Sha1, b"12345678901234567890");
+        check(
+            &totp,
+            [
+                "94287082", "07081804", "14050471", "89005924", "69279037", "65353130",
+            ],
+        );
+    }
+
+    #[test]
+    fn rfc6238_sha256() {
+        let totp = rfc6238(Algorithm::Sha256, b"12345678901234567890123456789012");
+        check(
+            &totp,
+            [
+                "46119246", "68084774", "67062674", "91819424", "90698825", "77737706",
+            ],
+        );
+    }
+
+    #[test]
+    fn rfc6238_sha512() {
+        let totp = rfc6238(
+            Algorithm::Sha512,
+            b"1234567890123456789012345678901234567890123456789012345678901234",
+        );
+        check(
+            &totp,
+            [
+                "90693936", "25091201", "99943326", "93441116", "38618901", "47863826",
+            ],
+        );
+    }
+
+    #[test]
+    fn uri_round_trip() {
+        let mut totp = Totp::new("JBSWY3DPEHPK3PXP", "alice@example.com").with_issuer("Example");
+        totp.algorithm = Algorithm::Sha256;
+        totp.digits = 8;
+        totp.period = 60;
+
+        let uri = totp.to_uri();
+        assert_eq!(
+            uri,
+            "otpauth://totp/Example:alice%40example.com?secret=JBSWY3DPEHPK3PXP\
+             &issuer=Example&algorithm=SHA256&digits=8&period=60"
+        );
+        assert_eq!(Totp::from_uri(&uri), Ok(totp));
+    }
+
+    #[test]
+    fn uri_round_trip_percent_encoded_label() {
+        let totp =
+            Totp::new("JBSWY3DPEHPK3PXP", "ana maría+otp@example.com").with_issuer("ACME Co");
+
+        let uri = totp.to_uri();
+        assert!(uri.starts_with("otpauth://totp/ACME%20Co:ana%20mar%C3%ADa%2Botp"));
+        assert_eq!(Totp::from_uri(&uri), Ok(totp));
+    }
+
+    #[test]
+    fn uri_round_trip_without_issuer() {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "alice");
+
+        let uri = totp.to_uri();
+        assert_eq!(
+            uri,
+            "otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&algorithm=SHA1&digits=6&period=30"
+        );
+        assert_eq!(Totp::from_uri(&uri), Ok(totp));
+    }
+
+    #[test]
+    fn uri_issuer_from_label_or_parameter() {
+        let label = Totp::from_uri("otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP").unwrap();
+        assert_eq!(label.issuer.as_deref(), Some("Example"));
+        assert_eq!(label.account, "alice");
+
+        let parameter =
+            Totp::from_uri("otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&issuer=Example").unwrap();
+        assert_eq!(parameter.issuer.as_deref(), Some("Example"));
+
+        let encoded = Totp::from_uri(
+            "otpauth://totp/ACME%20Co%3A%20alice?secret=JBSWY3DPEHPK3PXP&issuer=ACME%20Co",
+        )
+        .unwrap();
+        assert_eq!(encoded.issuer.as_deref(), Some("ACME Co"));
+        assert_eq!(encoded.account, "alice");
+    }
+
+    #[test]
+    fn uri_rejects_mismatched_issuer() {
+        assert_eq!(
+            Totp::from_uri("otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Other"),
+            Err(SecureError::InvalidUri)
+        );
+    }
 
-    let mut mac = Hmac::<Sha1>::new_from_slice(&key).unwrap();
-    mac.update(&msg);
-    let result = mac.finalize().into_bytes();
+    #[test]
+    fn uri_rejects_invalid_input() {
+        for uri in [
+            "otpauth://hotp/alice?secret=JBSWY3DPEHPK3PXP",
+            "otpauth://totp/?secret=JBSWY3DPEHPK3PXP",
+            "otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&digits=9",
+            "otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&period=0",
+            "otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&algorithm=MD5",
+        ] {
+            assert_eq!(Totp::from_uri(uri), Err(SecureError::InvalidUri), "{}", uri);
+        }
+        assert_eq!(
+            Totp::from_uri("otpauth://totp/alice?secret=not-base32"),
+            Err(SecureError::InvalidSecret)
+        );
+    }
 
-    let offset = (result[19] & 0x0f) as usize;
-    let binary_code = ((u32::from(result[offset]) & 0x7f) << 24)
-        | ((u32::from(result[offset + 1]) & 0xff) << 16)
-        | ((u32::from(result[offset + 2]) & 0xff) << 8)
-        | (u32::from(result[offset + 3]) & 0xff);
+    #[test]
+    fn drift_window_accepts_previous_step_only() {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "drift");
+        let now = 1_700_000_015;
+        let step = now / 30;
 
-    Ok(format!("{:06}", binary_code % 1_000_000))
+        let current = totp.generate(step).unwrap();
+        let previous = totp.generate(step - 1).unwrap();
+        let older = totp.generate(step - 2).unwrap();
+        let next = totp.generate(step + 1).unwrap();
+
+        assert_eq!(totp.verify_at(&current, now), Ok(step));
+        assert_eq!(totp.verify_at(&previous, now), Ok(step - 1));
+        assert_eq!(totp.verify_at(&older, now), Err(SecureError::InvalidCode));
+        assert_eq!(totp.verify_at(&next, now), Err(SecureError::InvalidCode));
+    }
+
+    #[test]
+    fn drift_window_at_step_boundary() {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "drift");
+        let code = totp.generate(100).unwrap();
+
+        assert_eq!(totp.verify_at(&code, 100 * 30), Ok(100));
+        assert_eq!(totp.verify_at(&code, 101 * 30 + 29), Ok(100));
+        assert_eq!(
+            totp.verify_at(&code, 102 * 30),
+            Err(SecureError::InvalidCode)
+        );
+    }
+
+    #[test]
+    fn validate_totp_checks_current_code() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let code = Totp::new("JBSWY3DPEHPK3PXP", "")
+            .generate(now / 30)
+            .unwrap();
+
+        assert_eq!(validate_totp("JBSWY3DPEHPK3PXP", &code, 30), Ok(()));
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert_eq!(
+            validate_totp("JBSWY3DPEHPK3PXP", &wrong, 30),
+            Err(SecureError::InvalidCode)
+        );
+    }
+
+    #[test]
+    fn validate_totp_rejects_malformed_codes() {
+        for code in ["", "12345", "1234567", "12a456", " 12345"] {
+            assert_eq!(
+                validate_totp("JBSWY3DPEHPK3PXP", code, 30),
+                Err(SecureError::InvalidCode),
+                "code {:?}",
+                code
+            );
+        }
+        assert_eq!(
+            validate_totp("JBSWY3DPEHPK3PXP", "123456", 0),
+            Err(SecureError::InvalidCode)
+        );
+        assert_eq!(
+            validate_totp("not base32!", "123456", 30),
+            Err(SecureError::InvalidSecret)
+        );
+    }
 }
@@ -0,0 +1,192 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{StatusCode, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tower::Layer;
+use tracing::error;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::{MiddlewareFuture, MiddlewareService, Subject, error_response, middleware_service};
+use crate::secure::{
+    SecureError,
+    apikey::{ApiKeyRecord, ApiKeyStore, verify_api_key},
+};
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Require an `X-API-Key` header carrying a key that holds every one of
+/// `scopes`.
+pub fn require_api_key<S: ApiKeyStore>(store: Arc<S>, scopes: &[&str]) -> ApiKeyLayer<S> {
+    ApiKeyLayer {
+        store,
+        scopes: scopes.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+pub struct ApiKeyLayer<S> {
+    store: Arc<S>,
+    scopes: Arc<[String]>,
+}
+
+impl<S> Clone for ApiKeyLayer<S> {
+    fn clone(&self) -> Self {
+        ApiKeyLayer {
+            store: self.store.clone(),
+            scopes: self.scopes.clone(),
+        }
+    }
+}
+
+impl<I, S: ApiKeyStore> Layer<I> for ApiKeyLayer<S> {
+    type Service = ApiKeyAuth<I, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, authenticate::<S>, inner)
+    }
+}
+
+pub type ApiKeyAuth<I, S> = MiddlewareService<ApiKeyLayer<S>, I>;
+
+fn authenticate<S: ApiKeyStore>(
+    State(layer): State<ApiKeyLayer<S>>,
+    mut req: Request,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(async move {
+        let Some(key) = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+        else {
+            return error_response(StatusCode::UNAUTHORIZED, "missing_api_key");
+        };
+
+        let record = match verify_api_key(layer.store.as_ref(), &key).await {
+            Ok(record) => record,
+            Err(SecureError::Store(err)) => {
+                error!(error.message = err, "api key store unavailable");
+                return error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable");
+            }
+            Err(_) => return error_response(StatusCode::UNAUTHORIZED, "invalid_api_key"),
+        };
+
+        if let Some(span) = req.extensions().get::<tracing::Span>() {
+            span.set_attribute("enduser.id", record.id.clone());
+        }
+        if !layer.scopes.iter().all(|scope| record.has_scope(scope)) {
+            return error_response(StatusCode::FORBIDDEN, "insufficient_scope");
+        }
+
+        req.extensions_mut().insert(Subject(record.id.clone()));
+        req.extensions_mut().insert(record);
+        next.run(req).await
+    })
+}
+
+impl<S> FromRequestParts<S> for ApiKeyRecord
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ApiKeyRecord>()
+            .cloned()
+            .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, "missing_api_key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure::apikey::{LIVE_PREFIX, MemoryApiKeyStore, generate_api_key};
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    struct UnavailableStore;
+
+    impl ApiKeyStore for UnavailableStore {
+        async fn get(&self, _hash: &str) -> Result<Option<ApiKeyRecord>, SecureError> {
+            Err(SecureError::Store("connection refused".into()))
+        }
+
+        async fn put(&self, _record: ApiKeyRecord) -> Result<(), SecureError> {
+            Err(SecureError::Store("connection refused".into()))
+        }
+
+        async fn remove(&self, _hash: &str) -> Result<(), SecureError> {
+            Err(SecureError::Store("connection refused".into()))
+        }
+    }
+
+    fn app<S: ApiKeyStore>(store: Arc<S>) -> Router {
+        Router::new()
+            .route("/", get(|record: ApiKeyRecord| async move { record.id }))
+            .layer(require_api_key(store, &["read", "write"]))
+    }
+
+    async fn call(app: Router, key: Option<&str>) -> (StatusCode, String) {
+        let mut req = Request::get("/");
+        if let Some(key) = key {
+            req = req.header(API_KEY_HEADER, key);
+        }
+        let response = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn store_with(scopes: &[&str]) -> (Arc<MemoryApiKeyStore>, String) {
+        let store = Arc::new(MemoryApiKeyStore::new());
+        let scopes = scopes.iter().map(|s| s.to_string()).collect();
+        let (key, record) = generate_api_key(LIVE_PREFIX, "svc", scopes, None).unwrap();
+        store.put(record).await.unwrap();
+        (store, key)
+    }
+
+    #[tokio::test]
+    async fn key_with_every_scope_is_accepted() {
+        let (store, key) = store_with(&["read", "write", "admin"]).await;
+        assert_eq!(
+            call(app(store), Some(&key)).await,
+            (StatusCode::OK, "svc".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_and_invalid_keys_are_rejected() {
+        let (store, _) = store_with(&["read", "write"]).await;
+        let (status, body) = call(app(store.clone()), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("missing_api_key"));
+
+        let (status, body) = call(app(store), Some("ck_live_unknown")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("invalid_api_key"));
+    }
+
+    #[tokio::test]
+    async fn key_missing_a_scope_is_forbidden() {
+        let (store, key) = store_with(&["read"]).await;
+        let (status, body) = call(app(store), Some(&key)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("insufficient_scope"));
+    }
+
+    #[tokio::test]
+    async fn store_outage_is_unavailable() {
+        let (status, body) = call(app(Arc::new(UnavailableStore)), Some("ck_live_key")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("unavailable"));
+    }
+}
@@ -0,0 +1,457 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderValue, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::{future::Future, marker::PhantomData, sync::Arc};
+use tower::Layer;
+use tracing::error;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::{MiddlewareFuture, MiddlewareService, error_response, middleware_service};
+use crate::{
+    client::{JwksClient, JwksError},
+    secure::{
+        SecureError,
+        jwt::{Claims, Keyring, Validation},
+    },
+};
+
+/// The verified caller of a request, inserted by [`BearerAuthLayer`].
+#[derive(Clone, Debug)]
+pub struct Authenticated<C> {
+    pub subject: String,
+    pub claims: C,
+}
+
+/// Identity of the caller, inserted by every authentication layer so that
+/// later layers do not need to know how the caller authenticated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subject(pub String);
+
+/// Checks a bearer token and resolves it to the caller it was issued to.
+///
+/// Implemented by [`JwtVerifier`] for self-contained tokens and by any
+/// `Fn(String) -> Future` closure, which is the easiest way to plug in an
+/// opaque token lookup.
+pub trait TokenVerifier: Send + Sync + 'static {
+    type Claims: Clone + Send + Sync + 'static;
+
+    fn verify(
+        &self,
+        token: &str,
+    ) -> impl Future<Output = Result<Authenticated<Self::Claims>, SecureError>> + Send;
+}
+
+impl<F, Fut, C> TokenVerifier for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Authenticated<C>, SecureError>> + Send,
+    C: Clone + Send + Sync + 'static,
+{
+    type Claims = C;
+
+    fn verify(
+        &self,
+        token: &str,
+    ) -> impl Future<Output = Result<Authenticated<Self::Claims>, SecureError>> + Send {
+        self(token.to_owned())
+    }
+}
+
+/// Claims granting scopes, for routes that check more than who the caller
+/// is, such as [`add_log_level_route`](super::add_log_level_route).
+pub trait Scopes {
+    fn has_scope(&self, scope: &str) -> bool;
+}
+
+/// Reads the `scope` claim, either a space-separated string as in RFC 9068
+/// or an array of strings.
+impl Scopes for Map<String, Value> {
+    fn has_scope(&self, scope: &str) -> bool {
+        match self.get("scope") {
+            Some(Value::String(scopes)) => scopes.split(' ').any(|s| s == scope),
+            Some(Value::Array(scopes)) => scopes.iter().any(|s| s.as_str() == Some(scope)),
+            _ => false,
+        }
+    }
+}
+
+impl<T: Scopes> Scopes for Claims<T> {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.custom.has_scope(scope)
+    }
+}
+
+enum KeySource {
+    Keyring(Arc<Keyring>),
+    Jwks(Arc<JwksClient>),
+}
+
+/// Verifies JWTs with a local keyring or an issuer's JWKS endpoint, using
+/// the `sub` claim as the subject.
+pub struct JwtVerifier<T> {
+    source: KeySource,
+    validation: Validation,
+    claims: PhantomData<fn() -> T>,
+}
+
+impl<T> JwtVerifier<T> {
+    pub fn new(keyring: Arc<Keyring>, validation: Validation) -> Self {
+        JwtVerifier {
+            source: KeySource::Keyring(keyring),
+            validation,
+            claims: PhantomData,
+        }
+    }
+
+    pub fn jwks(client: Arc<JwksClient>, validation: Validation) -> Self {
+        JwtVerifier {
+            source: KeySource::Jwks(client),
+            validation,
+            claims: PhantomData,
+        }
+    }
+}
+
+impl<T> TokenVerifier for JwtVerifier<T>
+where
+    T: DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    type Claims = Claims<T>;
+
+    async fn verify(&self, token: &str) -> Result<Authenticated<Self::Claims>, SecureError> {
+        let claims: Claims<T> = match &self.source {
+            KeySource::Keyring(keyring) => keyring.verify(token, &self.validation)?,
+            KeySource::Jwks(client) => {
+                client
+                    .verify(token, &self.validation)
+                    .await
+                    .map_err(|err| match err {
+                        JwksError::Secure(err) => err,
+                        // The issuer being unreachable says nothing about the
+                        // token, so report it as an outage.
+                        err => SecureError::Store(err.to_string()),
+                    })?
+            }
+        };
+        let subject = claims.sub.clone().ok_or(SecureError::InvalidClaim("sub"))?;
+        Ok(Authenticated { subject, claims })
+    }
+}
+
+/// Require a valid `Authorization: Bearer` header on the wrapped routes.
+pub fn bearer_auth<V: TokenVerifier>(verifier: Arc<V>) -> BearerAuthLayer<V> {
+    BearerAuthLayer {
+        verifier,
+        realm: None,
+    }
+}
+
+pub struct BearerAuthLayer<V> {
+    verifier: Arc<V>,
+    realm: Option<Arc<str>>,
+}
+
+impl<V> BearerAuthLayer<V> {
+    /// Realm advertised in the `WWW-Authenticate` challenge.
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+}
+
+impl<V> Clone for BearerAuthLayer<V> {
+    fn clone(&self) -> Self {
+        BearerAuthLayer {
+            verifier: self.verifier.clone(),
+            realm: self.realm.clone(),
+        }
+    }
+}
+
+impl<I, V: TokenVerifier> Layer<I> for BearerAuthLayer<V> {
+    type Service = BearerAuth<I, V>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, authenticate::<V>, inner)
+    }
+}
+
+pub type BearerAuth<I, V> = MiddlewareService<BearerAuthLayer<V>, I>;
+
+fn authenticate<V: TokenVerifier>(
+    State(layer): State<BearerAuthLayer<V>>,
+    mut req: Request,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(async move {
+        let token = match bearer_token(&req) {
+            Ok(token) => token.to_owned(),
+            Err(challenge) => return challenge.into_response(layer.realm.as_deref()),
+        };
+
+        match layer.verifier.verify(&token).await {
+            Ok(authenticated) => {
+                if let Some(span) = req.extensions().get::<tracing::Span>() {
+                    span.set_attribute("enduser.id", authenticated.subject.clone());
+                }
+                req.extensions_mut()
+                    .insert(Subject(authenticated.subject.clone()));
+                req.extensions_mut().insert(authenticated);
+                next.run(req).await
+            }
+            Err(SecureError::Store(err)) => {
+                error!(error.message = err, "token verifier unavailable");
+                error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+            }
+            Err(err) => Challenge::InvalidToken(err).into_response(layer.realm.as_deref()),
+        }
+    })
+}
+
+fn bearer_token(req: &Request) -> Result<&str, Challenge> {
+    let value = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .ok_or(Challenge::Missing)?
+        .to_str()
+        .map_err(|_| Challenge::InvalidRequest)?;
+    let (scheme, token) = value.split_once(' ').ok_or(Challenge::InvalidRequest)?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(Challenge::Missing);
+    }
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(Challenge::InvalidRequest);
+    }
+    Ok(token)
+}
+
+/// Authentication failures, reported as described in RFC 6750 section 3.
+pub(crate) enum Challenge {
+    Missing,
+    InvalidRequest,
+    InvalidToken(SecureError),
+}
+
+impl Challenge {
+    pub(crate) fn into_response(self, realm: Option<&str>) -> Response {
+        let (status, error, description) = match &self {
+            Challenge::Missing => (StatusCode::UNAUTHORIZED, None, None),
+            Challenge::InvalidRequest => (StatusCode::BAD_REQUEST, Some("invalid_request"), None),
+            Challenge::InvalidToken(err) => (
+                StatusCode::UNAUTHORIZED,
+                Some("invalid_token"),
+                Some(err.to_string()),
+            ),
+        };
+
+        let mut params = Vec::new();
+        if let Some(realm) = realm {
+            params.push(format!("realm={}", quoted_string(realm)));
+        }
+        if let Some(error) = error {
+            params.push(format!("error={}", quoted_string(error)));
+        }
+        if let Some(description) = description {
+            params.push(format!("error_description={}", quoted_string(&description)));
+        }
+
+        let challenge = if params.is_empty() {
+            "Bearer".to_owned()
+        } else {
+            format!("Bearer {}", params.join(", "))
+        };
+
+        let mut response = error_response(status, error.unwrap_or("unauthorized"));
+        if let Ok(value) = HeaderValue::from_str(&challenge) {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, value);
+        }
+        response
+    }
+}
+
+/// Quote `value` as an RFC 7230 quoted-string. Characters a header value
+/// cannot hold are replaced, so the challenge is never dropped.
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            ' ' | '\t' | '!'..='~' => quoted.push(c),
+            _ => quoted.push('?'),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl<S, C> FromRequestParts<S> for Authenticated<C>
+where
+    S: Send + Sync,
+    C: Clone + Send + Sync + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Authenticated<C>>()
+            .cloned()
+            .ok_or_else(|| Challenge::Missing.into_response(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure::jwt::Key;
+    use axum::{Extension, Router, body::Body, routing::get};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn verify(token: String) -> Result<Authenticated<String>, SecureError> {
+        match token.as_str() {
+            "good" => Ok(Authenticated {
+                subject: "user-1".into(),
+                claims: "claims".into(),
+            }),
+            "down" => Err(SecureError::Store("connection refused".into())),
+            _ => Err(SecureError::InvalidSignature),
+        }
+    }
+
+    async fn whoami(
+        Extension(Subject(subject)): Extension<Subject>,
+        authenticated: Authenticated<String>,
+    ) -> String {
+        assert_eq!(subject, authenticated.subject);
+        format!("{} {}", authenticated.subject, authenticated.claims)
+    }
+
+    fn app(realm: &str) -> Router {
+        Router::new()
+            .route("/", get(whoami))
+            .layer(bearer_auth(Arc::new(verify)).with_realm(realm))
+    }
+
+    async fn call(authorization: Option<&str>) -> Response {
+        let mut req = Request::get("/");
+        if let Some(authorization) = authorization {
+            req = req.header(header::AUTHORIZATION, authorization);
+        }
+        app("api")
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn challenge(response: &Response) -> &str {
+        response.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_token_reaches_the_handler() {
+        let response = call(Some("Bearer good")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"user-1 claims");
+
+        let response = call(Some("bearer   good ")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn missing_or_malformed_credentials_are_challenged() {
+        let response = call(None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge(&response), r#"Bearer realm="api""#);
+
+        let response = call(Some("Basic dXNlcjpwYXNz")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge(&response), r#"Bearer realm="api""#);
+
+        for malformed in ["Bearer", "Bearer  "] {
+            let response = call(Some(malformed)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                challenge(&response),
+                r#"Bearer realm="api", error="invalid_request""#
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_token_is_described() {
+        let response = call(Some("Bearer forged")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            challenge(&response),
+            r#"Bearer realm="api", error="invalid_token", error_description="invalid signature""#
+        );
+    }
+
+    #[tokio::test]
+    async fn verifier_outage_is_not_blamed_on_the_token() {
+        let response = call(Some("Bearer down")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[tokio::test]
+    async fn challenge_parameters_are_quoted() {
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let response = app("my \"quoted\" \\ realm\u{e9}")
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(
+            challenge(&response),
+            r#"Bearer realm="my \"quoted\" \\ realm?""#
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_jwks_endpoint_is_an_outage() {
+        let (key, _) = Key::generate_ed25519("kid").unwrap();
+        let claims = Claims::new(Map::new(), Duration::from_secs(60)).with_subject("user");
+        let token = crate::secure::jwt::encode(&key, &claims).unwrap();
+
+        // Nothing listens on the discard port.
+        let client = JwksClient::new("http://127.0.0.1:9/jwks.json").unwrap();
+        let verifier =
+            JwtVerifier::<Map<String, Value>>::jwks(Arc::new(client), Validation::default());
+        assert!(matches!(
+            verifier.verify(&token).await,
+            Err(SecureError::Store(_))
+        ));
+    }
+
+    #[test]
+    fn scope_claim_may_be_a_string_or_an_array() {
+        let claims = |scope: Value| {
+            let mut map = Map::new();
+            map.insert("scope".into(), scope);
+            map
+        };
+        assert!(claims("read admin".into()).has_scope("admin"));
+        assert!(!claims("read administrator".into()).has_scope("admin"));
+        assert!(claims(serde_json::json!(["read", "admin"])).has_scope("admin"));
+        assert!(!claims(serde_json::json!(["read"])).has_scope("admin"));
+        assert!(!Map::new().has_scope("admin"));
+    }
+}
@@ -0,0 +1,362 @@
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::{FromRequestParts, MatchedPath, NestedPath, Request, State},
+    http::{Method, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+    routing::MethodRouter,
+};
+use parking_lot::RwLock;
+use percent_encoding::percent_decode_str;
+use rustc_hash::FxHashSet;
+use std::sync::Arc;
+use tower::Layer;
+use tracing::{error, warn};
+
+use super::{
+    MiddlewareFuture, MiddlewareService, add_http_route, error_response, http::request_scheme,
+    middleware_service, session::Session,
+};
+use crate::secure::{constant_time_eq, generate_secret};
+
+const CSRF_HEADER: &str = "x-csrf-token";
+const CSRF_FIELD: &str = "_csrf";
+const SESSION_KEY: &str = "csrf_token";
+
+/// Largest form body read while looking for the token field.
+const DEFAULT_FORM_LIMIT: usize = 1024 * 1024;
+
+/// Protect cookie-authenticated routes against cross-site request forgery.
+///
+/// Requests with an unsafe method must come from the same origin, scheme and
+/// host, or from a [trusted origin](CsrfLayer::with_trusted_origin),
+/// according to their `Origin` or `Referer` header, and must carry the
+/// session's token in an `X-CSRF-Token` header or, for urlencoded forms, a
+/// `_csrf` field. Forms get the token from the [`CsrfToken`] extractor.
+///
+/// Must be applied inside a [session layer](super::session::SessionLayer).
+/// Routes registered with [`add_csrf_exempt_route`] are not checked.
+pub fn csrf_protect() -> CsrfLayer {
+    CsrfLayer {
+        trusted_origins: Arc::new(FxHashSet::default()),
+        exempt_routes: Arc::new(RwLock::new(FxHashSet::default())),
+        form_limit: DEFAULT_FORM_LIMIT,
+    }
+}
+
+/// Add a route like [`add_http_route`], which `layer` lets through
+/// unchecked. Meant for routes authenticated by other means, like signed
+/// webhooks.
+///
+/// The exemption belongs to `layer` and its clones, so `layer` must then be
+/// applied to `app` itself, which may then be nested. Applied to a router
+/// that `app` is nested in, the route is matched under its full path and is
+/// checked.
+pub fn add_csrf_exempt_route(
+    app: Router,
+    layer: &CsrfLayer,
+    path: &'static str,
+    method_router: MethodRouter,
+) -> Router {
+    layer.exempt_routes.write().insert(path);
+    add_http_route(app, path, method_router)
+}
+
+#[derive(Clone)]
+pub struct CsrfLayer {
+    trusted_origins: Arc<FxHashSet<String>>,
+    /// Shared with clones, so routes registered after the layer was cloned
+    /// are still exempt.
+    exempt_routes: Arc<RwLock<FxHashSet<&'static str>>>,
+    form_limit: usize,
+}
+
+impl CsrfLayer {
+    /// Also accept requests from `origin`, such as `https://app.example.com`.
+    pub fn with_trusted_origin(mut self, origin: &str) -> Self {
+        Arc::make_mut(&mut self.trusted_origins)
+            .insert(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_form_limit(mut self, limit: usize) -> Self {
+        self.form_limit = limit;
+        self
+    }
+}
+
+impl<I> Layer<I> for CsrfLayer {
+    type Service = CsrfGuard<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, check_csrf, inner)
+    }
+}
+
+pub type CsrfGuard<I> = MiddlewareService<CsrfLayer, I>;
+
+fn check_csrf(State(layer): State<CsrfLayer>, req: Request, next: Next) -> MiddlewareFuture {
+    Box::pin(async move {
+        if is_safe(req.method()) || layer.is_exempt(&req) {
+            return next.run(req).await;
+        }
+
+        if !layer.is_same_origin(&req) {
+            warn!("cross-origin request rejected");
+            return error_response(StatusCode::FORBIDDEN, "csrf_origin_mismatch");
+        }
+
+        let Some(session) = req.extensions().get::<Session>().cloned() else {
+            error!("csrf protection applied outside a session layer");
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "session_unavailable");
+        };
+        let Some(expected) = session.get::<String>(SESSION_KEY) else {
+            return error_response(StatusCode::FORBIDDEN, "csrf_token_missing");
+        };
+
+        let (req, submitted) = match submitted_token(req, layer.form_limit).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+        match submitted {
+            None => error_response(StatusCode::FORBIDDEN, "csrf_token_missing"),
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                next.run(req).await
+            }
+            Some(_) => error_response(StatusCode::FORBIDDEN, "csrf_token_invalid"),
+        }
+    })
+}
+
+impl CsrfLayer {
+    /// Whether the request was routed to an exempt route. Inside a nested
+    /// router the matched path includes the prefix it was nested under,
+    /// which the route was not registered with.
+    fn is_exempt(&self, req: &Request) -> bool {
+        let Some(matched) = req.extensions().get::<MatchedPath>() else {
+            return false;
+        };
+        let nested = req.extensions().get::<NestedPath>();
+        let path = nested
+            .and_then(|nested| matched.as_str().strip_prefix(nested.as_str()))
+            .unwrap_or(matched.as_str());
+        self.exempt_routes.read().contains(path)
+    }
+
+    /// Compare the `Origin`, or failing that the `Referer`, with the scheme
+    /// and `Host` of the request and with the trusted origins. Requests
+    /// carrying neither are let through to the token check, since some
+    /// proxies strip both.
+    fn is_same_origin(&self, req: &Request) -> bool {
+        let header_str = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let Some(source) = header_str(header::ORIGIN).or_else(|| header_str(header::REFERER))
+        else {
+            return true;
+        };
+
+        let Some((scheme, rest)) = source.split_once("://") else {
+            return false;
+        };
+        let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let origin = format!("{}://{}", scheme, host).to_ascii_lowercase();
+        if self.trusted_origins.contains(&origin) {
+            return true;
+        }
+        scheme.eq_ignore_ascii_case(&request_scheme(req))
+            && header_str(header::HOST)
+                .or_else(|| req.uri().authority().map(|a| a.as_str()))
+                .is_some_and(|expected| host.eq_ignore_ascii_case(expected))
+    }
+}
+
+/// Read the token from the header or, for urlencoded forms, the body, which
+/// is put back for the handler.
+async fn submitted_token(
+    req: Request,
+    limit: usize,
+) -> Result<(Request, Option<String>), Response> {
+    if let Some(token) = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()) {
+        let token = token.to_owned();
+        return Ok((req, Some(token)));
+    }
+
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((req, None));
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = to_bytes(body, limit).await else {
+        return Err(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+        ));
+    };
+    let token = std::str::from_utf8(&bytes).ok().and_then(|form| {
+        form.split('&').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            (name == CSRF_FIELD).then(|| {
+                percent_decode_str(&value.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+        })
+    });
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// The CSRF token of the current session, to embed in forms as a hidden
+/// `_csrf` field or send back in the `X-CSRF-Token` header. Created on first
+/// use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+        if let Some(token) = session.get::<String>(SESSION_KEY) {
+            return Ok(CsrfToken(token));
+        }
+
+        let token = generate_secret(32)
+            .and_then(|token| session.insert(SESSION_KEY, &token).map(|()| token))
+            .map_err(|err| {
+                error!(error.message = %err, "failed to create csrf token");
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "session_unavailable")
+            })?;
+        Ok(CsrfToken(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::session::{MemorySessionStore, store_session};
+    use axum::routing::post;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let csrf = csrf_protect().with_trusted_origin("https://app.example.com");
+        let app = Router::new().route("/form", post(|| async {}));
+        let app = add_csrf_exempt_route(app, &csrf, "/webhook", post(|| async {}));
+        app.layer(csrf)
+            .layer(store_session(Arc::new(MemorySessionStore::new())))
+    }
+
+    async fn status(path: &str, origin: Option<&str>) -> StatusCode {
+        let mut req = Request::post(path).header(header::HOST, "example.com");
+        if let Some(origin) = origin {
+            req = req.header(header::ORIGIN, origin);
+        }
+        app()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn error(path: &str, origin: &str) -> String {
+        let req = Request::post(path)
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn origin_must_match_scheme_and_host() {
+        assert_eq!(
+            error("/form", "http://example.com").await,
+            "csrf_token_missing"
+        );
+        assert_eq!(
+            error("/form", "https://example.com").await,
+            "csrf_origin_mismatch"
+        );
+        assert_eq!(
+            error("/form", "http://evil.example").await,
+            "csrf_origin_mismatch"
+        );
+        assert_eq!(
+            error("/form", "https://app.example.com").await,
+            "csrf_token_missing"
+        );
+    }
+
+    #[tokio::test]
+    async fn exempt_route_is_not_checked() {
+        assert_eq!(
+            status("/webhook", Some("https://evil.example")).await,
+            StatusCode::OK
+        );
+        assert_eq!(status("/form", None).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn exemptions_belong_to_their_layer() {
+        // Another layer in the same process knows nothing of `/webhook`.
+        let app = Router::new()
+            .route("/webhook", post(|| async {}))
+            .layer(csrf_protect())
+            .layer(store_session(Arc::new(MemorySessionStore::new())));
+        let req = Request::post("/webhook")
+            .header(header::HOST, "example.com")
+            .header(header::ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            app.oneshot(req).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status("/webhook", Some("https://evil.example")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn exemption_added_after_cloning_applies() {
+        let csrf = csrf_protect();
+        let applied = csrf.clone();
+        let app = add_csrf_exempt_route(Router::new(), &csrf, "/hook", post(|| async {}))
+            .layer(applied)
+            .layer(store_session(Arc::new(MemorySessionStore::new())));
+        let req = Request::post("/hook").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn exempt_route_survives_nesting() {
+        let csrf = csrf_protect();
+        let api = add_csrf_exempt_route(Router::new(), &csrf, "/hook", post(|| async {}))
+            .layer(csrf)
+            .layer(store_session(Arc::new(MemorySessionStore::new())));
+        let app = Router::new().nest("/api", api);
+        let req = Request::post("/api/hook").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::OK);
+    }
+}
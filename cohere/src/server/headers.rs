@@ -0,0 +1,271 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tower::Layer;
+use tracing::error;
+
+use super::{MiddlewareFuture, MiddlewareService, error_response, middleware_service};
+use crate::{env, secure::generate_secret};
+
+/// Replaced with the request's [`CspNonce`] in the Content-Security-Policy.
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+const STRICT_CSP: &str = "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+    style-src 'self' 'nonce-{nonce}'; object-src 'none'; base-uri 'none'; \
+    form-action 'self'; frame-ancestors 'none'";
+const PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=(), payment=(), usb=()";
+
+const HSTS: &str = "max-age=63072000; includeSubDomains";
+
+/// Set the usual security headers on every response that does not already
+/// carry them.
+///
+/// By default this sends HSTS, a nonce-based Content-Security-Policy and
+/// `X-Frame-Options: DENY`. When the environment loaded by
+/// [`env::parse`](crate::env::parse) is [`Env::Local`](crate::env::Env::Local),
+/// HSTS is left out, so browsers do not pin `localhost` to HTTPS, and the
+/// policy is only reported so violations show up without breaking pages.
+/// Headers set explicitly on the layer are sent as set in every environment.
+///
+/// Routes override a header by setting it themselves, or replace the whole
+/// set by wrapping the route in another `security_headers()` layer
+/// configured differently.
+pub fn security_headers() -> SecurityHeadersLayer {
+    SecurityHeadersLayer {
+        hsts: None,
+        csp: Some(STRICT_CSP.into()),
+        csp_report_only: None,
+        content_type_options: Some("nosniff".into()),
+        referrer_policy: Some("strict-origin-when-cross-origin".into()),
+        permissions_policy: Some(PERMISSIONS_POLICY.into()),
+        frame_options: None,
+    }
+}
+
+/// Each header is left out when `None`. The outer `None` of the headers
+/// that depend on the environment means they were not set explicitly.
+#[derive(Clone)]
+pub struct SecurityHeadersLayer {
+    hsts: Option<Option<Arc<str>>>,
+    csp: Option<Arc<str>>,
+    csp_report_only: Option<bool>,
+    content_type_options: Option<Arc<str>>,
+    referrer_policy: Option<Arc<str>>,
+    permissions_policy: Option<Arc<str>>,
+    frame_options: Option<Option<Arc<str>>>,
+}
+
+impl SecurityHeadersLayer {
+    pub fn with_hsts(mut self, value: Option<&str>) -> Self {
+        self.hsts = Some(value.map(Into::into));
+        self
+    }
+
+    /// Occurrences of `{nonce}` are replaced with the request's nonce.
+    pub fn with_csp(mut self, value: Option<&str>) -> Self {
+        self.csp = value.map(Into::into);
+        self
+    }
+
+    /// Send the policy as `Content-Security-Policy-Report-Only`.
+    pub fn with_csp_report_only(mut self, report_only: bool) -> Self {
+        self.csp_report_only = Some(report_only);
+        self
+    }
+
+    pub fn with_content_type_options(mut self, value: Option<&str>) -> Self {
+        self.content_type_options = value.map(Into::into);
+        self
+    }
+
+    pub fn with_referrer_policy(mut self, value: Option<&str>) -> Self {
+        self.referrer_policy = value.map(Into::into);
+        self
+    }
+
+    pub fn with_permissions_policy(mut self, value: Option<&str>) -> Self {
+        self.permissions_policy = value.map(Into::into);
+        self
+    }
+
+    pub fn with_frame_options(mut self, value: Option<&str>) -> Self {
+        self.frame_options = Some(value.map(Into::into));
+        self
+    }
+
+    fn headers(&self, nonce: Option<&str>) -> Vec<(HeaderName, String)> {
+        let local = env::is_local();
+        let hsts = match &self.hsts {
+            Some(hsts) => hsts.as_deref().map(str::to_owned),
+            None => (!local).then(|| HSTS.to_owned()),
+        };
+        let frame_options = match &self.frame_options {
+            Some(frame_options) => frame_options.as_deref().map(str::to_owned),
+            None => Some(if local { "SAMEORIGIN" } else { "DENY" }.to_owned()),
+        };
+
+        let csp_header = if self.csp_report_only.unwrap_or(local) {
+            header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+        } else {
+            header::CONTENT_SECURITY_POLICY
+        };
+        let csp = self.csp.as_ref().map(|csp| match nonce {
+            Some(nonce) => csp.replace(NONCE_PLACEHOLDER, nonce),
+            None => csp.to_string(),
+        });
+
+        [
+            (header::STRICT_TRANSPORT_SECURITY, hsts),
+            (csp_header, csp),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                self.content_type_options.as_deref().map(str::to_owned),
+            ),
+            (
+                header::REFERRER_POLICY,
+                self.referrer_policy.as_deref().map(str::to_owned),
+            ),
+            (
+                HeaderName::from_static("permissions-policy"),
+                self.permissions_policy.as_deref().map(str::to_owned),
+            ),
+            (header::X_FRAME_OPTIONS, frame_options),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+}
+
+impl<I> Layer<I> for SecurityHeadersLayer {
+    type Service = SecurityHeaders<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, set_headers, inner)
+    }
+}
+
+pub type SecurityHeaders<I> = MiddlewareService<SecurityHeadersLayer, I>;
+
+/// Marks a response already handled by an inner layer, so that a layer on
+/// a single route replaces the one on the whole router.
+#[derive(Clone)]
+struct Applied;
+
+fn set_headers(
+    State(layer): State<SecurityHeadersLayer>,
+    mut req: Request,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(async move {
+        // Nested layers share the nonce of the outermost one.
+        let nonce = match req.extensions().get::<CspNonce>() {
+            Some(CspNonce(nonce)) => Some(nonce.clone()),
+            None if layer
+                .csp
+                .as_ref()
+                .is_some_and(|csp| csp.contains(NONCE_PLACEHOLDER)) =>
+            {
+                match generate_secret(16) {
+                    Ok(nonce) => {
+                        req.extensions_mut().insert(CspNonce(nonce.clone()));
+                        Some(nonce)
+                    }
+                    Err(err) => {
+                        error!(error.message = %err, "failed to generate csp nonce");
+                        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error");
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut response = next.run(req).await;
+        if response.extensions().get::<Applied>().is_some() {
+            return response;
+        }
+        response.extensions_mut().insert(Applied);
+
+        let headers = response.headers_mut();
+        let has_csp = headers.contains_key(header::CONTENT_SECURITY_POLICY)
+            || headers.contains_key(header::CONTENT_SECURITY_POLICY_REPORT_ONLY);
+        for (name, value) in layer.headers(nonce.as_deref()) {
+            let is_csp = name == header::CONTENT_SECURITY_POLICY
+                || name == header::CONTENT_SECURITY_POLICY_REPORT_ONLY;
+            if headers.contains_key(&name) || (is_csp && has_csp) {
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        response
+    })
+}
+
+/// Nonce allowing inline `<script>` and `<style>` elements under the
+/// Content-Security-Policy of [`security_headers`]. Changes on every request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CspNonce(pub String);
+
+impl<S> FromRequestParts<S> for CspNonce
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CspNonce>().cloned().ok_or_else(|| {
+            error!("csp nonce requested on a route without a nonce-based policy");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, routing::get};
+    use tower::ServiceExt;
+
+    async fn headers(layer: SecurityHeadersLayer) -> axum::http::HeaderMap {
+        let app = Router::new()
+            .route("/", get(|CspNonce(nonce): CspNonce| async move { nonce }))
+            .layer(layer);
+        let response = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers().clone()
+    }
+
+    #[tokio::test]
+    async fn strict_until_local_env_is_loaded() {
+        let headers = headers(security_headers()).await;
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], HSTS);
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY_REPORT_ONLY));
+        let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(csp.contains("script-src 'self' 'nonce-"));
+        assert!(!csp.contains(NONCE_PLACEHOLDER));
+    }
+
+    #[tokio::test]
+    async fn explicit_settings_win() {
+        let layer = security_headers()
+            .with_hsts(None)
+            .with_frame_options(Some("SAMEORIGIN"))
+            .with_csp_report_only(true);
+        let headers = headers(layer).await;
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+        assert!(headers.contains_key(header::CONTENT_SECURITY_POLICY_REPORT_ONLY));
+    }
+}
@@ -1,37 +1,114 @@
 use axum::{
     Router,
-    extract::Request,
+    extract::{Request, connect_info::ConnectInfo},
     http::{self, StatusCode},
     middleware::{Next, from_fn},
     response::{Json, Response},
     routing::{MethodRouter, get},
     serve,
 };
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
 use opentelemetry::{global, propagation::Extractor};
+use parking_lot::RwLock;
 use serde_json::{Value, json};
-use std::net::SocketAddr;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use tower_http::compression::CompressionLayer;
 use tracing::info;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-const HEALTHCHECK_PATH: &str = "/healthz";
+use super::{PeerIdentity, https::TlsConnection, ipfilter::parse_nets};
+
+pub(super) const HEALTHCHECK_PATH: &str = "/healthz";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
 
 pub fn new_http() -> Router {
     Router::new()
 }
 
-async fn healthcheck() -> Json<Value> {
+pub(super) async fn healthcheck() -> Json<Value> {
     Json(json!({
         "success": true,
     }))
 }
 
-fn get_client_ip(req: &Request) -> String {
-    req.extensions()
-        .get::<axum::extract::connect_info::ConnectInfo<SocketAddr>>()
-        .unwrap()
+static TRUSTED_PROXIES: Lazy<RwLock<Vec<IpNet>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Trust the `X-Forwarded-For` header of requests coming from these CIDR
+/// ranges or addresses, such as the load balancers in front of the service.
+/// Without any, the client address is the address of the connection.
+pub fn set_trusted_proxies<S: AsRef<str>>(proxies: &[S]) -> Result<(), AddrParseError> {
+    *TRUSTED_PROXIES.write() = parse_nets(proxies)?;
+    Ok(())
+}
+
+/// The address of the client, skipping trusted proxies from the right of
+/// `X-Forwarded-For`, where they append the address they received the
+/// request from. IPv4-mapped IPv6 addresses, as reported by dual-stack
+/// listeners, are turned back into IPv4 ones.
+pub(super) fn client_ip(req: &Request) -> Option<IpAddr> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()?
         .ip()
-        .to_string()
+        .to_canonical();
+
+    let proxies = TRUSTED_PROXIES.read();
+    let trusted = |ip: &IpAddr| proxies.iter().any(|net| net.contains(ip));
+    if !trusted(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        let ip = ip.to_canonical();
+        client = ip;
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// The scheme the client used, taken from `X-Forwarded-Proto` when the
+/// connection comes from a trusted proxy.
+pub(super) fn request_scheme(req: &Request) -> String {
+    let from_proxy = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|peer| {
+            let ip = peer.ip().to_canonical();
+            TRUSTED_PROXIES.read().iter().any(|net| net.contains(&ip))
+        });
+    let forwarded = from_proxy
+        .then(|| req.headers().get(X_FORWARDED_PROTO)?.to_str().ok())
+        .flatten()
+        // The first proxy appends the scheme it received first.
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+
+    match (forwarded, req.uri().scheme_str()) {
+        (Some(scheme), _) | (None, Some(scheme)) => scheme.to_ascii_lowercase(),
+        (None, None) if req.extensions().get::<TlsConnection>().is_some() => "https".to_owned(),
+        (None, None) => "http".to_owned(),
+    }
+}
+
+fn get_client_ip(req: &Request) -> String {
+    client_ip(req).map_or_else(|| "unknown".to_owned(), |ip| ip.to_string())
 }
 
 fn get_user_agent(req: &Request) -> String {
@@ -51,7 +128,11 @@ pub fn add_http_route(app: Router, path: &'static str, method_router: MethodRout
     )
 }
 
-async fn http_request_trace(route: &str, req: Request, next: Next) -> Result<Response, StatusCode> {
+async fn http_request_trace(
+    route: &str,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
     let uri = req.uri();
 
     let ip = get_client_ip(&req);
@@ -79,6 +160,14 @@ async fn http_request_trace(route: &str, req: Request, next: Next) -> Result<Res
     );
     span.set_parent(parent_cx);
 
+    if let Some(peer) = req.extensions().get::<PeerIdentity>() {
+        span.set_attribute("tls.client.subject", peer.subject.clone());
+        span.set_attribute("tls.client.subject_alternative_names", peer.sans.join(", "));
+    }
+
+    // Let inner layers record attributes on the request span.
+    req.extensions_mut().insert(span.clone());
+
     let _enter = span.enter();
 
     let response = http_request_log(req, next).await;
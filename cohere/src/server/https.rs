@@ -0,0 +1,497 @@
+use anyhow::Context as _;
+use axum::{
+    Router,
+    extract::{FromRequestParts, connect_info::ConnectInfo},
+    http::{StatusCode, request::Parts},
+    response::Response,
+    routing::get,
+};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use parking_lot::RwLock;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::WebPkiClientVerifier,
+    },
+};
+use tower::Service;
+use tower_http::compression::CompressionLayer;
+use tracing::{debug, error, info};
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use super::{
+    error_response,
+    http::{HEALTHCHECK_PATH, healthcheck},
+};
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, which usually means the process ran out of
+/// file descriptors, rather than retrying in a busy loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where to find the server certificate and, for mutual TLS, the CAs that
+/// client certificates must chain to. All files are PEM encoded and are
+/// reloaded when they change.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    reload_interval: Duration,
+}
+
+impl TlsConfig {
+    /// `cert_path` holds the certificate chain, leaf first, and `key_path`
+    /// its private key.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+        }
+    }
+
+    /// Require clients to present a certificate issued by one of the CAs in
+    /// `path`, and expose it to handlers as a [`PeerIdentity`].
+    pub fn with_client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_ca_path = Some(path.into());
+        self
+    }
+
+    /// How often the files are checked for changes.
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [
+            Some(&self.cert_path),
+            Some(&self.key_path),
+            self.client_ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::as_path)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read certificates from {:?}", self.cert_path))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .with_context(|| format!("failed to read private key from {:?}", self.key_path))?;
+
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("failed to read client CAs from {:?}", path))?
+                {
+                    roots.add(cert?)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Identity from the verified client certificate of a mutual TLS
+/// connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Distinguished name, such as `CN=billing, O=Example`.
+    pub subject: String,
+    /// Subject alternative names, such as `DNS:billing.internal` or
+    /// `URI:spiffe://example/billing`.
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = parse_x509_certificate(der).ok()?;
+        let sans = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|ext| {
+                ext.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(v) => Some(format!("DNS:{}", v)),
+                        GeneralName::RFC822Name(v) => Some(format!("email:{}", v)),
+                        GeneralName::URI(v) => Some(format!("URI:{}", v)),
+                        GeneralName::IPAddress(v) => ip_address(v).map(|ip| format!("IP:{}", ip)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(PeerIdentity {
+            subject: cert.subject().to_string(),
+            sans,
+        })
+    }
+}
+
+fn ip_address(bytes: &[u8]) -> Option<std::net::IpAddr> {
+    match bytes.len() {
+        4 => Some(<[u8; 4]>::try_from(bytes).ok()?.into()),
+        16 => Some(<[u8; 16]>::try_from(bytes).ok()?.into()),
+        _ => None,
+    }
+}
+
+impl<S> FromRequestParts<S> for PeerIdentity
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<PeerIdentity>()
+            .cloned()
+            .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, "client_certificate_required"))
+    }
+}
+
+/// Marks requests received by [`serve_https`].
+#[derive(Clone, Copy, Debug)]
+pub(super) struct TlsConnection;
+
+/// Like [`serve_http`](super::serve_http), but over TLS. Certificates are
+/// reloaded when their files change; connections already open keep the
+/// certificate they were established with.
+pub async fn serve_https(app: Router, port: u16, tls: TlsConfig) -> anyhow::Result<()> {
+    let config = tls.server_config()?;
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("server listening on {} (tls)", addr);
+
+    serve_tls(listener, app, tls, config, super::shutdown_signal()).await;
+    Ok(())
+}
+
+/// Accept TLS connections on `listener` until `signal` resolves, then wait
+/// for the open connections to finish. `config` is reloaded from `tls` for
+/// as long as the server runs.
+async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    tls: TlsConfig,
+    config: ServerConfig,
+    signal: impl Future<Output = ()>,
+) {
+    let config = Arc::new(RwLock::new(Arc::new(config)));
+    // Stopped when the server stops, or when this future is dropped.
+    let _reload = AbortOnDrop(tokio::spawn(reload_certificates(tls, config.clone())));
+
+    let app = app
+        .layer(CompressionLayer::new())
+        .route(HEALTHCHECK_PATH, get(healthcheck));
+
+    // Every connection holds a receiver, so `closed` resolves once the last
+    // one has finished after shutdown.
+    let (shutdown, connections) = watch::channel(());
+    tokio::pin!(signal);
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(error.message = %err, "failed to accept connection");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let acceptor = TlsAcceptor::from(config.read().clone());
+        let app = app.clone();
+        let mut connections = connections.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                .await
+            {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!(error.message = %err, client.address = %remote_addr.ip(), "tls handshake failed");
+                    return;
+                }
+                Err(_) => return,
+            };
+            let peer = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| PeerIdentity::from_der(cert));
+
+            let service = service_fn(move |mut req: hyper::Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(remote_addr));
+                req.extensions_mut().insert(TlsConnection);
+                if let Some(peer) = &peer {
+                    req.extensions_mut().insert(peer.clone());
+                }
+                app.clone().call(req)
+            });
+
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = connections.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = result {
+                debug!(error.message = %err, "connection closed with error");
+            }
+        });
+    }
+
+    drop(connections);
+    shutdown.send_replace(());
+    shutdown.closed().await;
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Poll the certificate files and swap in a new configuration when they
+/// change. A configuration that fails to load is retried on the next tick,
+/// which also covers the certificate and key being replaced one at a time.
+async fn reload_certificates(tls: TlsConfig, config: Arc<RwLock<Arc<ServerConfig>>>) {
+    let mut modified = tls.modified();
+    let mut interval = tokio::time::interval(tls.reload_interval);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let current = tls.modified();
+        if current == modified {
+            continue;
+        }
+        match tls.server_config() {
+            Ok(loaded) => {
+                *config.write() = Arc::new(loaded);
+                modified = current;
+                info!("reloaded tls certificate");
+            }
+            Err(err) => error!(error.message = %err, "failed to reload tls certificate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Request;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, pki_types::ServerName},
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    async fn whoami(req: Request) -> String {
+        match req.extensions().get::<PeerIdentity>() {
+            Some(peer) => format!("{} [{}]", peer.subject, peer.sans.join(", ")),
+            None => "anonymous".to_owned(),
+        }
+    }
+
+    /// Serve on a local port until the returned sender is dropped.
+    async fn serve(tls: TlsConfig) -> (SocketAddr, tokio::sync::oneshot::Sender<()>) {
+        let config = tls.server_config().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let app = Router::new().route("/whoami", get(whoami));
+        tokio::spawn(serve_tls(listener, app, tls, config, async {
+            let _ = stopped.await;
+        }));
+        (addr, stop)
+    }
+
+    fn client_config(client: Option<&str>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(fixture("ca.pem")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        match client {
+            Some(name) => {
+                let certs = CertificateDer::pem_file_iter(fixture(&format!("{}.pem", name)))
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                let key = PrivateKeyDer::from_pem_file(fixture(&format!("{}.key", name))).unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    /// `GET /whoami` over TLS, returning the raw response or the error that
+    /// ended the connection.
+    async fn get_whoami(addr: SocketAddr, client: Option<&str>) -> std::io::Result<String> {
+        let connector = TlsConnector::from(Arc::new(client_config(client)));
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await?;
+        stream
+            .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn serves_over_tls() {
+        let tls = TlsConfig::new(fixture("server.pem"), fixture("server.key"));
+        let (addr, _stop) = serve(tls).await;
+
+        let response = get_whoami(addr, None).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("anonymous"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_exposes_peer_identity() {
+        let tls = TlsConfig::new(fixture("server.pem"), fixture("server.key"))
+            .with_client_ca(fixture("ca.pem"));
+        let (addr, _stop) = serve(tls).await;
+
+        let response = get_whoami(addr, Some("client")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.ends_with(
+                "CN=billing, O=Example [DNS:billing.internal, URI:spiffe://example/billing]"
+            ),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_rejects_untrusted_or_missing_client_certificates() {
+        let tls = TlsConfig::new(fixture("server.pem"), fixture("server.key"))
+            .with_client_ca(fixture("ca.pem"));
+        let (addr, _stop) = serve(tls).await;
+
+        for client in [Some("untrusted-client"), None] {
+            // With TLS 1.3 the client learns of the rejection on its first
+            // read rather than during the handshake.
+            let response = get_whoami(addr, client).await;
+            assert!(
+                !matches!(&response, Ok(body) if !body.is_empty()),
+                "{:?}: {:?}",
+                client,
+                response
+            );
+        }
+    }
+
+    /// The certificate the server presents in a handshake.
+    async fn presented_certificate(addr: SocketAddr) -> CertificateDer<'static> {
+        let connector = TlsConnector::from(Arc::new(client_config(None)));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(server_name, stream).await.unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    fn certificate(name: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(fixture(name)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn changed_certificate_files_are_reloaded() {
+        let dir = std::env::temp_dir().join(format!("cohere-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("server.pem"), dir.join("server.key"));
+        std::fs::copy(fixture("server.pem"), &cert).unwrap();
+        std::fs::copy(fixture("server.key"), &key).unwrap();
+
+        let tls = TlsConfig::new(&cert, &key).with_reload_interval(Duration::from_millis(20));
+        let (addr, stop) = serve(tls).await;
+        assert_eq!(presented_certificate(addr).await, certificate("server.pem"));
+
+        // Modification times may not change within the same tick.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::copy(fixture("renewed-server.key"), &key).unwrap();
+        std::fs::copy(fixture("renewed-server.pem"), &cert).unwrap();
+
+        let renewed = certificate("renewed-server.pem");
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while presented_certificate(addr).await != renewed {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "certificate not reloaded"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        drop(stop);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+};
+use ipnet::IpNet;
+use parking_lot::RwLock;
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::{AddrParseError, IpAddr},
+    sync::Arc,
+};
+use tower::Layer;
+use tracing::{debug, warn};
+
+use super::{
+    MiddlewareFuture, MiddlewareService, error_response, http::client_ip, middleware_service,
+};
+
+/// CIDR ranges a request is checked against. Deny rules win over allow
+/// rules, and when there are no allow rules every address not denied is
+/// allowed.
+///
+/// Ranges are written like `10.0.0.0/8`, or as a single address. Rules can be
+/// deserialized from config files, or built with [`IpRules::new`] from the
+/// `Vec<String>` fields that [`env::parse`](crate::env::parse) reads from
+/// comma-separated variables.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct IpRules {
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Vec<IpNet>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Vec<IpNet>,
+}
+
+impl IpRules {
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<Self, AddrParseError> {
+        Ok(IpRules {
+            allow: parse_nets(allow)?,
+            deny: parse_nets(deny)?,
+        })
+    }
+
+    /// The decision for `ip`, with the rule that made it. An IPv4-mapped
+    /// IPv6 address is checked as the IPv4 address it carries.
+    fn check(&self, ip: IpAddr) -> Result<Option<IpNet>, Rejection> {
+        let ip = ip.to_canonical();
+        if let Some(net) = self.deny.iter().find(|net| net.contains(&ip)) {
+            return Err(Rejection::Denied(*net));
+        }
+        if self.allow.is_empty() {
+            return Ok(None);
+        }
+        match self.allow.iter().find(|net| net.contains(&ip)) {
+            Some(net) => Ok(Some(*net)),
+            None => Err(Rejection::NotAllowed),
+        }
+    }
+}
+
+enum Rejection {
+    Denied(IpNet),
+    NotAllowed,
+}
+
+/// Rules shared by every route of an [`ip_filter`] layer, which can be
+/// replaced while the server runs.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    rules: RwLock<IpRules>,
+}
+
+impl IpFilter {
+    pub fn new(rules: IpRules) -> Self {
+        IpFilter {
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn rules(&self) -> IpRules {
+        self.rules.read().clone()
+    }
+
+    pub fn replace(&self, rules: IpRules) {
+        *self.rules.write() = rules;
+    }
+}
+
+/// Only let through requests whose client address passes the rules of
+/// `filter`. The address is resolved like the `client.address` of the
+/// request logs, so set the [trusted proxies](super::set_trusted_proxies)
+/// when running behind a load balancer.
+pub fn ip_filter(filter: Arc<IpFilter>) -> IpFilterLayer {
+    IpFilterLayer { filter }
+}
+
+#[derive(Clone)]
+pub struct IpFilterLayer {
+    filter: Arc<IpFilter>,
+}
+
+impl<I> Layer<I> for IpFilterLayer {
+    type Service = IpFilterGuard<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, filter_ip, inner)
+    }
+}
+
+pub type IpFilterGuard<I> = MiddlewareService<IpFilterLayer, I>;
+
+fn filter_ip(State(layer): State<IpFilterLayer>, req: Request, next: Next) -> MiddlewareFuture {
+    Box::pin(async move {
+        let Some(ip) = client_ip(&req) else {
+            warn!("request rejected: client address unknown");
+            return error_response(StatusCode::FORBIDDEN, "ip_unknown");
+        };
+
+        let decision = layer.filter.rules.read().check(ip);
+        match decision {
+            Ok(rule) => {
+                debug!(
+                    client.address = %ip,
+                    rule = rule.map(|net| net.to_string()),
+                    "request allowed by ip filter"
+                );
+                next.run(req).await
+            }
+            Err(Rejection::Denied(net)) => {
+                warn!(
+                    client.address = %ip,
+                    rule = %net,
+                    "request rejected: address denied"
+                );
+                error_response(StatusCode::FORBIDDEN, "ip_denied")
+            }
+            Err(Rejection::NotAllowed) => {
+                warn!(
+                    client.address = %ip,
+                    "request rejected: address not in any allowed range"
+                );
+                error_response(StatusCode::FORBIDDEN, "ip_not_allowed")
+            }
+        }
+    })
+}
+
+/// Parse a CIDR range, treating a bare address as a range of one.
+fn parse_net(value: &str) -> Result<IpNet, AddrParseError> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+}
+
+pub(super) fn parse_nets<S: AsRef<str>>(values: &[S]) -> Result<Vec<IpNet>, AddrParseError> {
+    values
+        .iter()
+        .map(|value| value.as_ref())
+        .filter(|value| !value.trim().is_empty())
+        .map(parse_net)
+        .collect()
+}
+
+fn deserialize_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    let values = Vec::<String>::deserialize(deserializer)?;
+    parse_nets(&values).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::set_trusted_proxies;
+    use axum::{Router, body::Body, extract::connect_info::ConnectInfo, routing::get};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    /// The only proxies any test trusts, so tests running in parallel agree
+    /// on the global setting. Other tests use peers outside this range.
+    const PROXIES: &str = "10.99.0.0/16";
+
+    fn rules(allow: &[&str], deny: &[&str]) -> IpRules {
+        IpRules::new(allow, deny).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let rules = rules(&["10.0.0.0/8"], &["10.1.0.0/16", "10.2.3.4"]);
+        assert_eq!(
+            rules.check(ip("10.0.0.1")).ok(),
+            Some(Some("10.0.0.0/8".parse().unwrap()))
+        );
+        assert!(matches!(
+            rules.check(ip("10.1.2.3")),
+            Err(Rejection::Denied(net)) if net.to_string() == "10.1.0.0/16"
+        ));
+        assert!(matches!(
+            rules.check(ip("10.2.3.4")),
+            Err(Rejection::Denied(_))
+        ));
+        assert!(rules.check(ip("10.2.3.5")).is_ok());
+        assert!(matches!(
+            rules.check(ip("192.168.0.1")),
+            Err(Rejection::NotAllowed)
+        ));
+    }
+
+    #[test]
+    fn without_allow_rules_everything_not_denied_passes() {
+        let rules = rules(&[], &["192.168.0.0/16", "2001:db8::/32"]);
+        assert_eq!(rules.check(ip("8.8.8.8")).ok(), Some(None));
+        assert!(rules.check(ip("192.168.1.1")).is_err());
+        assert!(rules.check(ip("2001:db8::1")).is_err());
+        assert!(rules.check(ip("2001:db9::1")).is_ok());
+        assert_eq!(IpRules::default().check(ip("::1")).ok(), Some(None));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let denied = rules(&[], &["10.0.0.0/8"]);
+        assert!(matches!(
+            denied.check(ip("::ffff:10.0.0.5")),
+            Err(Rejection::Denied(_))
+        ));
+        let allowed = rules(&["10.0.0.0/8"], &[]);
+        assert!(allowed.check(ip("::ffff:10.0.0.5")).is_ok());
+    }
+
+    #[test]
+    fn rules_parse_ranges_and_single_addresses() {
+        assert_eq!(
+            rules(&[" 10.0.0.1 ", ""], &["::1"]),
+            IpRules {
+                allow: vec!["10.0.0.1/32".parse().unwrap()],
+                deny: vec!["::1/128".parse().unwrap()],
+            }
+        );
+        assert!(IpRules::new(&["10.0.0.0/33"], &[]).is_err());
+        assert!(IpRules::new(&["not an address"], &[]).is_err());
+
+        let parsed: IpRules =
+            serde_json::from_str(r#"{"allow": ["10.0.0.0/8"], "deny": ["10.1.0.0/16"]}"#).unwrap();
+        assert_eq!(parsed, rules(&["10.0.0.0/8"], &["10.1.0.0/16"]));
+    }
+
+    async fn status(filter: IpRules, peer: &str, forwarded_for: Option<&str>) -> StatusCode {
+        set_trusted_proxies(&[PROXIES]).unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(ip_filter(Arc::new(IpFilter::new(filter))));
+        let mut req = Request::get("/");
+        if let Some(forwarded_for) = forwarded_for {
+            req = req.header("x-forwarded-for", forwarded_for);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn mapped_peer_address_is_denied() {
+        let deny = rules(&[], &["172.16.0.0/12"]);
+        assert_eq!(
+            status(deny.clone(), "[::ffff:172.16.0.5]:443", None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(deny, "[::ffff:172.32.0.5]:443", None).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let allow = rules(&["203.0.113.0/24"], &[]);
+        // An untrusted peer cannot claim an allowed address.
+        assert_eq!(
+            status(allow.clone(), "198.51.100.7:443", Some("203.0.113.9")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(allow, "203.0.113.9:443", Some("198.51.100.7")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_is_followed_through_trusted_proxies() {
+        let deny = rules(&[], &["198.51.100.0/24"]);
+        // The client appended by the proxy is checked, not the proxy.
+        assert_eq!(
+            status(deny.clone(), "10.99.0.1:443", Some("198.51.100.7")).await,
+            StatusCode::FORBIDDEN
+        );
+        // Trusted hops are skipped from the right, up to the first address
+        // no trusted proxy vouches for; anything further left is ignored.
+        assert_eq!(
+            status(
+                deny.clone(),
+                "10.99.0.1:443",
+                Some("198.51.100.7, 203.0.113.9, 10.99.0.2")
+            )
+            .await,
+            StatusCode::OK
+        );
+        // Forwarded addresses are canonicalized too.
+        assert_eq!(
+            status(deny.clone(), "10.99.0.1:443", Some("::ffff:198.51.100.7")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(deny, "[::ffff:10.99.0.1]:443", Some("198.51.100.7")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_client_address_is_rejected() {
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(ip_filter(Arc::new(IpFilter::default())));
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(
+            app.oneshot(req).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use axum::{
+    Router,
+    http::header,
+    response::{IntoResponse, Json},
+    routing::get,
+};
+use std::sync::Arc;
+
+use super::add_http_route;
+use crate::secure::jwt::Keyring;
+
+const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+/// How long clients may cache the published keys. Kept short so a rotated
+/// key is picked up well before the previous one is removed.
+const JWKS_MAX_AGE: u32 = 300;
+
+/// Publish the public keys of the keyring at `/.well-known/jwks.json`.
+pub fn add_jwks_route(app: Router, keyring: Arc<Keyring>) -> Router {
+    add_http_route(
+        app,
+        JWKS_PATH,
+        get(move || {
+            let keyring = keyring.clone();
+            async move {
+                (
+                    [(
+                        header::CACHE_CONTROL,
+                        format!("public, max-age={}", JWKS_MAX_AGE),
+                    )],
+                    Json(keyring.jwks()),
+                )
+                    .into_response()
+            }
+        }),
+    )
+}
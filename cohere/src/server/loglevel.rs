@@ -0,0 +1,248 @@
+use axum::{
+    Extension, Router,
+    extract::Request,
+    http::StatusCode,
+    middleware::{Next, from_fn},
+    response::{IntoResponse, Json, Response},
+    routing::put,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, warn};
+
+use super::{
+    Authenticated, Scopes, Subject, TokenVerifier, add_http_route, bearer_auth, error_response,
+};
+use crate::instrument::{
+    self, FilterError,
+    audit::{self, LogFilterChange},
+};
+
+const LOG_LEVEL_PATH: &str = "/admin/loglevel";
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    /// Directives such as `info,cohere=debug`.
+    filter: String,
+    /// Seconds after which the previous filter is restored.
+    #[serde(default)]
+    ttl: Option<u64>,
+}
+
+/// Let callers accepted by `verifier` whose claims grant `scope` read and
+/// change the log filter at `/admin/loglevel`.
+///
+/// `GET` returns the current filter and `PUT` replaces it with a body like
+/// `{"filter": "debug", "ttl": 300}`, where the optional `ttl` restores the
+/// previous filter after that many seconds, up to a day. Every change is
+/// logged with the subject that made it before it is applied, since the new
+/// filter may drop the line, and recorded in the
+/// [audit log](crate::instrument::audit) when one is installed.
+pub fn add_log_level_route<V>(app: Router, verifier: Arc<V>, scope: &str) -> Router
+where
+    V: TokenVerifier,
+    V::Claims: Scopes,
+{
+    let scope: Arc<str> = scope.into();
+    add_http_route(
+        app,
+        LOG_LEVEL_PATH,
+        put(set_log_level)
+            .get(get_log_level)
+            .layer(from_fn(move |req: Request, next: Next| {
+                require_scope::<V::Claims>(scope.clone(), req, next)
+            }))
+            .layer(bearer_auth(verifier)),
+    )
+}
+
+async fn require_scope<C>(scope: Arc<str>, req: Request, next: Next) -> Response
+where
+    C: Scopes + Clone + Send + Sync + 'static,
+{
+    let granted = req
+        .extensions()
+        .get::<Authenticated<C>>()
+        .is_some_and(|authenticated| authenticated.claims.has_scope(&scope));
+    if !granted {
+        return error_response(StatusCode::FORBIDDEN, "insufficient_scope");
+    }
+    next.run(req).await
+}
+
+async fn get_log_level() -> Response {
+    match instrument::log_filter() {
+        Some(filter) => Json(json!({
+            "success": true,
+            "filter": filter,
+        }))
+        .into_response(),
+        None => error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    }
+}
+
+async fn set_log_level(
+    Extension(Subject(subject)): Extension<Subject>,
+    Json(body): Json<LogLevelRequest>,
+) -> Response {
+    let ttl = body.ttl.filter(|ttl| *ttl > 0).map(Duration::from_secs);
+    warn!(
+        enduser.id = %subject,
+        filter = %body.filter,
+        ttl = body.ttl,
+        "changing log filter"
+    );
+    match instrument::set_log_filter(&body.filter, ttl) {
+        Ok(()) => {
+            if audit::is_initialized() {
+                let change = LogFilterChange {
+                    actor: subject,
+                    filter: body.filter.clone(),
+                    ttl: ttl.map(|ttl| ttl.as_secs()),
+                };
+                if let Err(err) = audit::record(&change).await {
+                    error!(error.message = %err, "failed to audit log filter change");
+                }
+            }
+            Json(json!({
+                "success": true,
+                "filter": body.filter,
+                "ttl": ttl.map(|ttl| ttl.as_secs()),
+            }))
+            .into_response()
+        }
+        Err(FilterError::Invalid(_)) => error_response(StatusCode::BAD_REQUEST, "invalid_filter"),
+        Err(FilterError::TtlTooLong) => error_response(StatusCode::BAD_REQUEST, "invalid_ttl"),
+        Err(err) => {
+            error!(error.message = %err, "failed to change log filter");
+            error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instrument::{MAX_FILTER_TTL, audit::AuditLog, filter_tests as filter},
+        secure::SecureError,
+    };
+    use axum::{
+        body::{Body, to_bytes},
+        http::header,
+    };
+    use serde_json::{Map, Value};
+    use std::io::{self, Write};
+    use tower::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn verify(token: String) -> Result<Authenticated<Map<String, Value>>, SecureError> {
+        let scope = match token.as_str() {
+            "admin" => "logs:write read",
+            "reader" => "read",
+            _ => return Err(SecureError::InvalidSignature),
+        };
+        let mut claims = Map::new();
+        claims.insert("scope".into(), scope.into());
+        Ok(Authenticated {
+            subject: token,
+            claims,
+        })
+    }
+
+    async fn call(token: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+        let app = add_log_level_route(Router::new(), Arc::new(verify), "logs:write");
+        let mut req = match &body {
+            Some(_) => Request::put(LOG_LEVEL_PATH),
+            None => Request::get(LOG_LEVEL_PATH),
+        }
+        .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let response = app.oneshot(req.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn callers_need_a_token_with_the_scope() {
+        let _guard = filter::isolated("info").await;
+        let change = json!({"filter": "debug"});
+
+        let (status, _) = call(None, Some(change.clone())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(Some("forged"), Some(change.clone())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call(Some("reader"), Some(change)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "insufficient_scope");
+        let (status, _) = call(Some("reader"), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        assert_eq!(instrument::log_filter().as_deref(), Some("info"));
+        assert!(filter::applied().is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_changes_are_rejected() {
+        let _guard = filter::isolated("info").await;
+
+        let (status, body) = call(Some("admin"), Some(json!({"filter": "cohere=loud"}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_filter");
+
+        let ttl = MAX_FILTER_TTL.as_secs() + 1;
+        let (status, body) =
+            call(Some("admin"), Some(json!({"filter": "debug", "ttl": ttl}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_ttl");
+
+        assert_eq!(instrument::log_filter().as_deref(), Some("info"));
+        assert!(filter::applied().is_empty());
+    }
+
+    #[tokio::test]
+    async fn changes_are_applied_and_audited() {
+        let _guard = filter::isolated("info").await;
+        let buffer = Buffer::default();
+        audit::init(AuditLog::new(buffer.clone()));
+
+        let (status, body) = call(Some("admin"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["filter"], "info");
+
+        let (status, body) =
+            call(Some("admin"), Some(json!({"filter": "debug", "ttl": 300}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"success": true, "filter": "debug", "ttl": 300})
+        );
+        assert_eq!(instrument::log_filter().as_deref(), Some("debug"));
+
+        let audited = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let record: Value = serde_json::from_str(audited.lines().last().unwrap()).unwrap();
+        assert_eq!(record["kind"], "log_filter_change");
+        assert_eq!(
+            record["event"],
+            json!({"actor": "admin", "filter": "debug", "ttl": 300})
+        );
+    }
+}
@@ -1,6 +1,71 @@
+mod apikey;
+mod auth;
+mod csrf;
+mod headers;
 mod http;
+mod https;
+mod ipfilter;
+mod jwks;
+mod loglevel;
+pub mod session;
+mod signature;
+mod stepup;
 
-pub use http::{add_http_route, new_http, serve_http};
+pub use apikey::{ApiKeyAuth, ApiKeyLayer, require_api_key};
+pub use auth::{
+    Authenticated, BearerAuth, BearerAuthLayer, JwtVerifier, Scopes, Subject, TokenVerifier,
+    bearer_auth,
+};
+pub use csrf::{CsrfGuard, CsrfLayer, CsrfToken, add_csrf_exempt_route, csrf_protect};
+pub use headers::{CspNonce, SecurityHeaders, SecurityHeadersLayer, security_headers};
+pub use http::{add_http_route, new_http, serve_http, set_trusted_proxies};
+pub use https::{PeerIdentity, TlsConfig, serve_https};
+pub use ipfilter::{IpFilter, IpFilterGuard, IpFilterLayer, IpRules, ip_filter};
+pub use jwks::add_jwks_route;
+pub use loglevel::add_log_level_route;
+pub use signature::{SignatureAuth, SignatureLayer, verify_signature};
+pub use stepup::{OtpAuth, OtpLayer, OtpSecretStore, require_otp};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{FromFn, Next, from_fn_with_state},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::{future::Future, pin::Pin};
+use tower::Layer;
+
+/// Body returned when a middleware rejects a request, with a machine-readable
+/// `error` reason.
+pub(crate) fn error_response(status: StatusCode, error: &str) -> Response {
+    (
+        status,
+        Json(json!({
+            "success": false,
+            "error": error,
+        })),
+    )
+        .into_response()
+}
+
+type MiddlewareFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// Middleware function of a layer, which receives the layer itself as state.
+/// A function pointer rather than an `async fn` so the service type of the
+/// layer can be named.
+type Middleware<L> = fn(State<L>, Request, Next) -> MiddlewareFuture;
+
+/// Service produced by a layer `L` around `I`.
+type MiddlewareService<L, I> = FromFn<Middleware<L>, L, I, (State<L>, Request)>;
+
+fn middleware_service<L: Clone, I>(
+    layer: &L,
+    middleware: Middleware<L>,
+    inner: I,
+) -> MiddlewareService<L, I> {
+    from_fn_with_state(layer.clone(), middleware).layer(inner)
+}
 
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -0,0 +1,564 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+use tower::Layer;
+use tracing::error;
+
+use super::{MiddlewareFuture, MiddlewareService, error_response, middleware_service};
+use crate::{
+    env,
+    secure::{SecureError, crypto::Keyring, generate_secret},
+    time::now,
+};
+
+const DEFAULT_COOKIE_NAME: &str = "session";
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Browsers drop cookies larger than this.
+const MAX_COOKIE_LEN: usize = 4096;
+
+/// Expired sessions are purged once the memory store holds this many, rather
+/// than on every save.
+const PURGE_THRESHOLD: usize = 4096;
+
+/// Storage for server-side sessions.
+pub trait SessionStore: Send + Sync + 'static {
+    fn load(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<Map<String, Value>>, SecureError>> + Send;
+
+    fn save(
+        &self,
+        id: &str,
+        data: Map<String, Value>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<(), SecureError>> + Send;
+
+    fn remove(&self, id: &str) -> impl Future<Output = Result<(), SecureError>> + Send;
+}
+
+/// [`SessionStore`] keeping sessions in a map. Sessions are lost on restart
+/// and not shared between instances.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: DashMap<String, (Map<String, Value>, u64)>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        MemorySessionStore::default()
+    }
+
+    /// Drop every expired session. Expired sessions are never returned, so
+    /// this only reclaims memory.
+    pub fn evict_expired(&self) {
+        let now = now();
+        self.sessions.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, id: &str) -> Result<Option<Map<String, Value>>, SecureError> {
+        Ok(self
+            .sessions
+            .get(id)
+            .filter(|session| session.1 > now())
+            .map(|session| session.0.clone()))
+    }
+
+    async fn save(
+        &self,
+        id: &str,
+        data: Map<String, Value>,
+        ttl: Duration,
+    ) -> Result<(), SecureError> {
+        if self.sessions.len() >= PURGE_THRESHOLD {
+            self.evict_expired();
+        }
+        self.sessions
+            .insert(id.to_owned(), (data, now().saturating_add(ttl.as_secs())));
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), SecureError> {
+        self.sessions.remove(id);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        })
+    }
+}
+
+enum Backend<S> {
+    Cookie(Arc<Keyring>),
+    Store(Arc<S>),
+}
+
+impl<S> Clone for Backend<S> {
+    fn clone(&self) -> Self {
+        match self {
+            Backend::Cookie(keyring) => Backend::Cookie(keyring.clone()),
+            Backend::Store(store) => Backend::Store(store.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CookieConfig {
+    name: Arc<str>,
+    ttl: Duration,
+    /// `None` to decide per request from the environment.
+    secure: Option<bool>,
+    same_site: SameSite,
+}
+
+/// Keep the whole session in a cookie encrypted with `keyring`, which also
+/// authenticates it. Suited to small sessions; the cookie must stay under
+/// 4 KiB, and a copy of it stays valid until it expires even after
+/// [`Session::destroy`], so use [`store_session`] when sessions must be
+/// revocable.
+pub fn cookie_session(keyring: Arc<Keyring>) -> SessionLayer {
+    SessionLayer::new(Backend::Cookie(keyring))
+}
+
+/// Keep sessions in `store`, with only a random id in the cookie.
+pub fn store_session<S: SessionStore>(store: Arc<S>) -> SessionLayer<S> {
+    SessionLayer::new(Backend::Store(store))
+}
+
+/// Loads the session before the wrapped routes run and saves it afterwards
+/// if it changed.
+///
+/// Cookies are `HttpOnly` and `SameSite=Lax`, and `Secure` unless the
+/// environment loaded by [`env::parse`] is [`Env::Local`](env::Env::Local),
+/// so sessions work over plain HTTP during development.
+///
+/// A response whose session cannot be saved, such as a cookie session
+/// grown past 4 KiB, is replaced with a 500 error.
+pub struct SessionLayer<S = MemorySessionStore> {
+    backend: Backend<S>,
+    config: CookieConfig,
+}
+
+impl<S> SessionLayer<S> {
+    fn new(backend: Backend<S>) -> Self {
+        SessionLayer {
+            backend,
+            config: CookieConfig {
+                name: DEFAULT_COOKIE_NAME.into(),
+                ttl: DEFAULT_TTL,
+                secure: None,
+                same_site: SameSite::Lax,
+            },
+        }
+    }
+
+    pub fn with_cookie_name(mut self, name: &str) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    /// How long a session lives after it was last changed.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = ttl;
+        self
+    }
+
+    /// Always or never mark cookies `Secure`, whatever the environment.
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.config.secure = Some(secure);
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.config.same_site = same_site;
+        self
+    }
+}
+
+impl<S> Clone for SessionLayer<S> {
+    fn clone(&self) -> Self {
+        SessionLayer {
+            backend: self.backend.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<I, S: SessionStore> Layer<I> for SessionLayer<S> {
+    type Service = SessionService<I, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, with_session::<S>, inner)
+    }
+}
+
+pub type SessionService<I, S> = MiddlewareService<SessionLayer<S>, I>;
+
+fn with_session<S: SessionStore>(
+    State(layer): State<SessionLayer<S>>,
+    mut req: Request,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(async move {
+        let cookie = cookie_value(req.headers(), &layer.config.name);
+        let state = match layer.load(cookie).await {
+            Ok(state) => state,
+            Err(err) => {
+                error!(error.message = %err, "session store unavailable");
+                return error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable");
+            }
+        };
+
+        let session = Session {
+            state: Arc::new(Mutex::new(state)),
+        };
+        req.extensions_mut().insert(session.clone());
+        let mut response = next.run(req).await;
+
+        let state = session.state.lock().clone();
+        match layer.save(state).await {
+            Ok(Some(cookie)) => {
+                if let Ok(value) = HeaderValue::from_str(&cookie) {
+                    response.headers_mut().append(header::SET_COOKIE, value);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!(error.message = %err, "failed to save session");
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, "session_unavailable");
+            }
+        }
+        response
+    })
+}
+
+/// Contents of an encrypted session cookie.
+#[derive(Serialize, Deserialize)]
+struct CookiePayload {
+    id: String,
+    exp: u64,
+    data: Map<String, Value>,
+}
+
+impl<S: SessionStore> SessionLayer<S> {
+    async fn load(&self, cookie: Option<String>) -> Result<SessionState, SecureError> {
+        let Some(cookie) = cookie else {
+            return SessionState::new();
+        };
+
+        match &self.backend {
+            Backend::Cookie(keyring) => {
+                let payload = URL_SAFE_NO_PAD
+                    .decode(&cookie)
+                    .ok()
+                    .and_then(|ciphertext| {
+                        keyring
+                            .decrypt(&ciphertext, self.config.name.as_bytes())
+                            .ok()
+                    })
+                    .and_then(|plaintext| serde_json::from_slice::<CookiePayload>(&plaintext).ok())
+                    .filter(|payload| payload.exp > now());
+                Ok(match payload {
+                    Some(payload) => SessionState::loaded(payload.id, payload.data),
+                    None => SessionState::new()?,
+                })
+            }
+            Backend::Store(store) => Ok(match store.load(&cookie).await? {
+                Some(data) => SessionState::loaded(cookie, data),
+                None => SessionState::new()?,
+            }),
+        }
+    }
+
+    /// Persist the session if it changed, returning the `Set-Cookie` value
+    /// to send back.
+    async fn save(&self, state: SessionState) -> Result<Option<String>, SecureError> {
+        if state.destroyed {
+            if let (Backend::Store(store), Some(id)) = (&self.backend, &state.loaded_id) {
+                store.remove(id).await?;
+            }
+            return Ok(state.loaded_id.map(|_| self.removal_cookie()));
+        }
+        let rotated = state.loaded_id.as_ref() != Some(&state.id);
+        if !state.modified && !rotated {
+            return Ok(None);
+        }
+        if state.loaded_id.is_none() && state.data.is_empty() {
+            return Ok(None);
+        }
+
+        let value = match &self.backend {
+            Backend::Cookie(keyring) => {
+                let payload = CookiePayload {
+                    id: state.id,
+                    exp: now().saturating_add(self.config.ttl.as_secs()),
+                    data: state.data,
+                };
+                let plaintext = serde_json::to_vec(&payload)
+                    .map_err(|err| SecureError::Store(err.to_string()))?;
+                let value = URL_SAFE_NO_PAD
+                    .encode(keyring.encrypt(&plaintext, self.config.name.as_bytes())?);
+                if value.len() + self.config.name.len() > MAX_COOKIE_LEN {
+                    return Err(SecureError::Store("session cookie exceeds 4 KiB".into()));
+                }
+                value
+            }
+            Backend::Store(store) => {
+                if rotated && let Some(old) = &state.loaded_id {
+                    store.remove(old).await?;
+                }
+                store.save(&state.id, state.data, self.config.ttl).await?;
+                state.id
+            }
+        };
+
+        Ok(Some(self.cookie(&value, self.config.ttl.as_secs())))
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite={}",
+            self.config.name, value, max_age, self.config.same_site
+        );
+        if self.config.secure.unwrap_or_else(|| !env::is_local()) {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    fn removal_cookie(&self) -> String {
+        self.cookie("", 0)
+    }
+}
+
+#[derive(Clone)]
+struct SessionState {
+    id: String,
+    /// Id the session was loaded under, `None` for a new session.
+    loaded_id: Option<String>,
+    data: Map<String, Value>,
+    modified: bool,
+    destroyed: bool,
+}
+
+impl SessionState {
+    fn loaded(id: String, data: Map<String, Value>) -> Self {
+        SessionState {
+            id: id.clone(),
+            loaded_id: Some(id),
+            data,
+            modified: false,
+            destroyed: false,
+        }
+    }
+
+    fn new() -> Result<Self, SecureError> {
+        Ok(SessionState {
+            id: new_id()?,
+            loaded_id: None,
+            data: Map::new(),
+            modified: false,
+            destroyed: false,
+        })
+    }
+}
+
+/// The session of the current request, inserted by [`SessionLayer`].
+///
+/// Changes are saved when the response is sent.
+#[derive(Clone)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session").finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    /// Id of the session, which changes on [`rotate`](Self::rotate).
+    pub fn id(&self) -> String {
+        self.state.lock().id.clone()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.state.lock();
+        state
+            .data
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), SecureError> {
+        let value =
+            serde_json::to_value(value).map_err(|err| SecureError::Store(err.to_string()))?;
+        let mut state = self.state.lock();
+        state.data.insert(key.to_owned(), value);
+        state.modified = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.state.lock();
+        let value = state.data.remove(key);
+        state.modified |= value.is_some();
+        value
+    }
+
+    /// Issue a new session id, keeping the data. Call this whenever the
+    /// privileges of the session change, such as on login, so an id planted
+    /// before then cannot be used afterwards.
+    pub fn rotate(&self) -> Result<(), SecureError> {
+        let id = new_id()?;
+        self.state.lock().id = id;
+        Ok(())
+    }
+
+    /// Remove the session and clear its cookie, such as on logout.
+    pub fn destroy(&self) {
+        let mut state = self.state.lock();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+impl<S> FromRequestParts<S> for Session
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Session>().cloned().ok_or_else(|| {
+            error!("session requested on a route without a session layer");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "session_unavailable")
+        })
+    }
+}
+
+/// Find the cookie called `name` in the `Cookie` headers.
+pub(crate) fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+fn new_id() -> Result<String, SecureError> {
+    generate_secret(32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure::crypto;
+    use axum::{Router, body::Body, extract::Path, routing::get};
+    use tower::ServiceExt;
+
+    fn keyring() -> Arc<Keyring> {
+        let keyring = Keyring::new();
+        keyring.insert(crypto::Key::generate(1).unwrap().0);
+        Arc::new(keyring)
+    }
+
+    async fn set(session: Session, Path(size): Path<usize>) {
+        session.insert("data", "x".repeat(size)).unwrap();
+    }
+
+    async fn get_data(session: Session) -> String {
+        session.get("data").unwrap_or_default()
+    }
+
+    fn app(layer: SessionLayer) -> Router {
+        Router::new()
+            .route("/set/{size}", get(set))
+            .route("/get", get(get_data))
+            .layer(layer)
+    }
+
+    async fn get_set_cookie(app: Router, uri: &str) -> (StatusCode, Option<String>) {
+        let response = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .map(|v| v.to_str().unwrap().to_owned());
+        (response.status(), cookie)
+    }
+
+    #[tokio::test]
+    async fn cookie_is_secure_until_local_env_is_loaded() {
+        let (status, cookie) = get_set_cookie(app(cookie_session(keyring())), "/set/8").await;
+        assert_eq!(status, StatusCode::OK);
+        let cookie = cookie.unwrap();
+        assert!(cookie.ends_with("; Secure"), "{}", cookie);
+        assert!(cookie.contains("HttpOnly; SameSite=Lax"));
+
+        let layer = cookie_session(keyring()).with_secure(false);
+        let (_, cookie) = get_set_cookie(app(layer), "/set/8").await;
+        assert!(!cookie.unwrap().contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn cookie_session_round_trip() {
+        let app = app(cookie_session(keyring()));
+        let (_, cookie) = get_set_cookie(app.clone(), "/set/8").await;
+        let cookie = cookie.unwrap();
+        let pair = cookie.split(';').next().unwrap();
+
+        let response = app
+            .oneshot(
+                Request::get("/get")
+                    .header(header::COOKIE, pair)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"xxxxxxxx");
+    }
+
+    #[tokio::test]
+    async fn oversized_cookie_session_fails_the_response() {
+        let (status, cookie) = get_set_cookie(app(cookie_session(keyring())), "/set/4096").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(cookie, None);
+    }
+}
@@ -0,0 +1,112 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+};
+use std::sync::Arc;
+use tower::Layer;
+use tracing::warn;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::{MiddlewareFuture, MiddlewareService, Subject, error_response, middleware_service};
+use crate::secure::{
+    SecureError,
+    signature::{
+        CONTENT_DIGEST_HEADER, SIGNATURE_HEADER, SIGNATURE_INPUT_HEADER, SignatureHeaders,
+        SignatureVerifier, SignedRequest,
+    },
+};
+
+/// Largest body buffered to check its digest.
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Require requests signed by [`send_signed_http`](crate::client::send_signed_http)
+/// with one of the verifier's keys. The key id becomes the request [`Subject`].
+pub fn verify_signature(verifier: Arc<SignatureVerifier>) -> SignatureLayer {
+    SignatureLayer {
+        verifier,
+        body_limit: DEFAULT_BODY_LIMIT,
+    }
+}
+
+#[derive(Clone)]
+pub struct SignatureLayer {
+    verifier: Arc<SignatureVerifier>,
+    body_limit: usize,
+}
+
+impl SignatureLayer {
+    pub fn with_body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+}
+
+impl<I> Layer<I> for SignatureLayer {
+    type Service = SignatureAuth<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, authenticate, inner)
+    }
+}
+
+pub type SignatureAuth<I> = MiddlewareService<SignatureLayer, I>;
+
+fn authenticate(State(layer): State<SignatureLayer>, req: Request, next: Next) -> MiddlewareFuture {
+    Box::pin(async move {
+        let Some(headers) = signature_headers(req.headers()) else {
+            return error_response(StatusCode::UNAUTHORIZED, "missing_signature");
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let Ok(body) = to_bytes(body, layer.body_limit).await else {
+            return error_response(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large");
+        };
+
+        let verified = layer.verifier.verify(
+            &SignedRequest {
+                method: parts.method.as_str(),
+                path: parts.uri.path(),
+                query: parts.uri.query(),
+                body: &body,
+            },
+            &headers,
+        );
+        let key_id = match verified {
+            Ok(key_id) => key_id,
+            Err(err) => {
+                let reason = match err {
+                    SecureError::Expired => "signature_expired",
+                    SecureError::Replayed => "signature_replayed",
+                    _ => "invalid_signature",
+                };
+                warn!(
+                    url.path = parts.uri.path(),
+                    "request signature rejected: {}", reason
+                );
+                return error_response(StatusCode::UNAUTHORIZED, reason);
+            }
+        };
+
+        if let Some(span) = parts.extensions.get::<tracing::Span>() {
+            span.set_attribute("enduser.id", key_id.clone());
+        }
+        parts.extensions.insert(Subject(key_id));
+        next.run(Request::from_parts(parts, Body::from(body))).await
+    })
+}
+
+fn signature_headers(headers: &HeaderMap) -> Option<SignatureHeaders> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+    };
+    Some(SignatureHeaders {
+        content_digest: header(CONTENT_DIGEST_HEADER)?,
+        signature_input: header(SIGNATURE_INPUT_HEADER)?,
+        signature: header(SIGNATURE_HEADER)?,
+    })
+}
@@ -0,0 +1,347 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+};
+use dashmap::DashMap;
+use std::{future::Future, sync::Arc, time::Duration};
+use tower::Layer;
+use tracing::{error, warn};
+
+use super::{MiddlewareFuture, MiddlewareService, Subject, error_response, middleware_service};
+use crate::{
+    secure::{SecureError, totp::Totp},
+    time::now,
+};
+
+const OTP_HEADER: &str = "x-otp";
+
+/// Failed codes allowed before the subject is locked out.
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Attempts that no longer matter are purged once this many subjects are
+/// tracked, rather than on every check.
+const PURGE_THRESHOLD: usize = 4096;
+
+/// Looks up the TOTP enrollment of a subject.
+pub trait OtpSecretStore: Send + Sync + 'static {
+    fn totp(&self, subject: &str)
+    -> impl Future<Output = Result<Option<Totp>, SecureError>> + Send;
+}
+
+/// Require a valid `X-OTP` header from the authenticated subject.
+///
+/// Must be applied inside an authentication layer such as
+/// [`bearer_auth`](super::bearer_auth). Used codes and failed attempts are
+/// tracked by the layer, so clone one layer for every sensitive route rather
+/// than creating one per route.
+pub fn require_otp<S: OtpSecretStore>(store: Arc<S>) -> OtpLayer<S> {
+    OtpLayer {
+        store,
+        attempts: Arc::new(DashMap::new()),
+    }
+}
+
+#[derive(Default)]
+struct Attempts {
+    last_counter: Option<u64>,
+    failures: u32,
+    locked_until: Option<u64>,
+    /// Once passed, the lockout is over, failures are forgotten and the last
+    /// used code can no longer verify, so the entry can be dropped.
+    expires_at: u64,
+}
+
+pub struct OtpLayer<S> {
+    store: Arc<S>,
+    attempts: Arc<DashMap<String, Attempts>>,
+}
+
+impl<S> Clone for OtpLayer<S> {
+    fn clone(&self) -> Self {
+        OtpLayer {
+            store: self.store.clone(),
+            attempts: self.attempts.clone(),
+        }
+    }
+}
+
+impl<I, S: OtpSecretStore> Layer<I> for OtpLayer<S> {
+    type Service = OtpAuth<I, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        middleware_service(self, verify_otp::<S>, inner)
+    }
+}
+
+pub type OtpAuth<I, S> = MiddlewareService<OtpLayer<S>, I>;
+
+fn verify_otp<S: OtpSecretStore>(
+    State(layer): State<OtpLayer<S>>,
+    req: Request,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(async move {
+        let Some(Subject(subject)) = req.extensions().get::<Subject>().cloned() else {
+            return error_response(StatusCode::UNAUTHORIZED, "unauthenticated");
+        };
+        let Some(otp) = req
+            .headers()
+            .get(OTP_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+        else {
+            return error_response(StatusCode::UNAUTHORIZED, "otp_required");
+        };
+
+        let reason = match layer.check(&subject, &otp).await {
+            Ok(()) => return next.run(req).await,
+            Err(SecureError::Store(err)) => {
+                error!(error.message = err, "otp secret store unavailable");
+                return error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable");
+            }
+            Err(SecureError::InvalidSecret) => "otp_not_enrolled",
+            Err(SecureError::Replayed) => "otp_replayed",
+            Err(SecureError::Locked) => "otp_locked",
+            Err(_) => "otp_invalid",
+        };
+        warn!(
+            enduser.id = subject,
+            "step-up verification failed: {}", reason
+        );
+        error_response(StatusCode::UNAUTHORIZED, reason)
+    })
+}
+
+impl<S: OtpSecretStore> OtpLayer<S> {
+    async fn check(&self, subject: &str, otp: &str) -> Result<(), SecureError> {
+        // Cheap early rejection; checked again below, since other attempts
+        // may lock the subject while the secret is being looked up.
+        if self
+            .attempts
+            .get(subject)
+            .and_then(|a| a.locked_until)
+            .is_some_and(|until| now() < until)
+        {
+            return Err(SecureError::Locked);
+        }
+
+        let totp = self
+            .store
+            .totp(subject)
+            .await?
+            .ok_or(SecureError::InvalidSecret)?;
+        let result = totp.verify(otp);
+
+        let now = now();
+        if self.attempts.len() >= PURGE_THRESHOLD {
+            self.attempts.retain(|_, a| a.expires_at > now);
+        }
+        let mut attempts = self.attempts.entry(subject.to_owned()).or_default();
+        if attempts.expires_at <= now {
+            *attempts = Attempts::default();
+        }
+        if attempts.locked_until.is_some_and(|until| now < until) {
+            return Err(SecureError::Locked);
+        }
+        match result {
+            Ok(counter) if attempts.last_counter.is_some_and(|last| counter <= last) => {
+                Err(SecureError::Replayed)
+            }
+            Ok(counter) => {
+                // A code verifies during its own time step and the next one.
+                *attempts = Attempts {
+                    last_counter: Some(counter),
+                    expires_at: counter.saturating_add(2).saturating_mul(totp.period),
+                    ..Attempts::default()
+                };
+                Ok(())
+            }
+            Err(err) => {
+                let until = now.saturating_add(LOCKOUT.as_secs());
+                attempts.expires_at = attempts.expires_at.max(until);
+                attempts.failures += 1;
+                if attempts.failures >= MAX_FAILURES {
+                    attempts.failures = 0;
+                    attempts.locked_until = Some(until);
+                    return Err(SecureError::Locked);
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Enrolled(Totp);
+
+    impl OtpSecretStore for Enrolled {
+        async fn totp(&self, _subject: &str) -> Result<Option<Totp>, SecureError> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    fn layer() -> (OtpLayer<Enrolled>, Totp) {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "alice");
+        (require_otp(Arc::new(Enrolled(totp.clone()))), totp)
+    }
+
+    fn current_code(totp: &Totp) -> String {
+        totp.generate(now() / totp.period).unwrap()
+    }
+
+    /// Holds every lookup until `count` checks are waiting, so they all pass
+    /// the early lockout check before any of them records its result.
+    struct Concurrent {
+        totp: Totp,
+        barrier: tokio::sync::Barrier,
+    }
+
+    impl OtpSecretStore for Concurrent {
+        async fn totp(&self, _subject: &str) -> Result<Option<Totp>, SecureError> {
+            self.barrier.wait().await;
+            Ok(Some(self.totp.clone()))
+        }
+    }
+
+    fn concurrent_layer(count: usize) -> (OtpLayer<Concurrent>, Totp) {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", "alice");
+        let store = Concurrent {
+            totp: totp.clone(),
+            barrier: tokio::sync::Barrier::new(count),
+        };
+        (require_otp(Arc::new(store)), totp)
+    }
+
+    #[tokio::test]
+    async fn used_code_is_replayed_until_it_expires() {
+        let (layer, totp) = layer();
+        let code = current_code(&totp);
+
+        layer.check("alice", &code).await.unwrap();
+        assert_eq!(
+            layer.check("alice", &code).await,
+            Err(SecureError::Replayed)
+        );
+
+        let expires_at = layer.attempts.get("alice").unwrap().expires_at;
+        assert!(expires_at > now());
+        assert!(expires_at <= now() + 2 * totp.period);
+    }
+
+    #[tokio::test]
+    async fn failures_lock_the_subject() {
+        let (layer, totp) = layer();
+        for _ in 1..MAX_FAILURES {
+            assert_eq!(
+                layer.check("alice", "000000x").await,
+                Err(SecureError::InvalidCode)
+            );
+        }
+        assert_eq!(
+            layer.check("alice", "000000x").await,
+            Err(SecureError::Locked)
+        );
+        assert_eq!(
+            layer.check("alice", &current_code(&totp)).await,
+            Err(SecureError::Locked)
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_attempts_are_purged() {
+        let (layer, totp) = layer();
+        for i in 0..PURGE_THRESHOLD {
+            layer.attempts.insert(
+                format!("user-{}", i),
+                Attempts {
+                    last_counter: Some(1),
+                    expires_at: now() - 1,
+                    ..Attempts::default()
+                },
+            );
+        }
+        layer.attempts.get_mut("user-0").unwrap().expires_at = now() + 60;
+
+        layer.check("alice", &current_code(&totp)).await.unwrap();
+        assert_eq!(layer.attempts.len(), 2);
+        assert!(layer.attempts.contains_key("user-0"));
+    }
+
+    #[tokio::test]
+    async fn expired_failures_are_forgotten() {
+        let (layer, totp) = layer();
+        layer.attempts.insert(
+            "alice".into(),
+            Attempts {
+                failures: MAX_FAILURES - 1,
+                expires_at: now() - 1,
+                ..Attempts::default()
+            },
+        );
+        assert_eq!(
+            layer.check("alice", "000000x").await,
+            Err(SecureError::InvalidCode)
+        );
+        assert_eq!(layer.attempts.get("alice").unwrap().failures, 1);
+        layer.check("alice", &current_code(&totp)).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn parallel_failures_cannot_exceed_the_limit() {
+        let count = 4 * MAX_FAILURES as usize;
+        let (layer, _) = concurrent_layer(count);
+        let guesses: Vec<_> = (0..count)
+            .map(|_| {
+                let layer = layer.clone();
+                tokio::spawn(async move { layer.check("alice", "000000x").await })
+            })
+            .collect();
+
+        let mut invalid = 0;
+        for guess in guesses {
+            match guess.await.unwrap() {
+                Err(SecureError::InvalidCode) => invalid += 1,
+                Err(SecureError::Locked) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(invalid, MAX_FAILURES - 1);
+        assert!(layer.attempts.get("alice").unwrap().locked_until > Some(now()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn valid_code_racing_failures_does_not_lift_the_lockout() {
+        let count = MAX_FAILURES as usize + 1;
+        let (layer, totp) = concurrent_layer(count);
+        let guesses: Vec<_> = (0..MAX_FAILURES)
+            .map(|_| {
+                let layer = layer.clone();
+                tokio::spawn(async move { layer.check("alice", "000000x").await })
+            })
+            .collect();
+        let valid = {
+            let layer = layer.clone();
+            let code = current_code(&totp);
+            tokio::spawn(async move { layer.check("alice", &code).await })
+        };
+
+        for guess in guesses {
+            assert!(guess.await.unwrap().is_err());
+        }
+        let valid = valid.await.unwrap();
+        let attempts = layer.attempts.get("alice").unwrap();
+        if valid.is_ok() {
+            // The valid code was recorded first, then the failures locked
+            // the subject.
+            assert!(attempts.last_counter.is_some());
+        } else {
+            assert_eq!(valid, Err(SecureError::Locked));
+        }
+        assert!(attempts.locked_until > Some(now()));
+    }
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# Regenerates the certificates used by the server::https tests.
+set -e
+cd "$(dirname "$0")"
+key() { openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out $1 2>/dev/null; }
+ca() { # name cn
+  key $1.key
+  openssl req -x509 -new -key $1.key -subj "/CN=$2" -days 36500 -sha256 \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out $1.pem
+}
+leaf() { # name ca cn san eku
+  key $1.key
+  openssl req -new -key $1.key -subj "/CN=$3/O=Example" -out /tmp/$1.csr
+  printf "basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=$5\nsubjectAltName=$4\n" > /tmp/$1.ext
+  openssl x509 -req -in /tmp/$1.csr -CA $2.pem -CAkey $2.key -CAcreateserial -days 36500 -sha256 -extfile /tmp/$1.ext -out $1.pem 2>/dev/null
+}
+ca ca "Cohere Test CA"
+ca untrusted-ca "Untrusted Test CA"
+leaf server ca localhost "DNS:localhost,IP:127.0.0.1" serverAuth
+leaf renewed-server ca localhost "DNS:localhost,IP:127.0.0.1" serverAuth
+leaf client ca billing "DNS:billing.internal,URI:spiffe://example/billing" clientAuth
+leaf untrusted-client untrusted-ca billing "DNS:billing.internal" clientAuth
+rm -f *.srl ca.key untrusted-ca.key